@@ -19,4 +19,29 @@ impl Error for GeneralError{}
 
 pub fn make_my_result<T, E: 'static + Error>(original_result: Result<T, E>) -> MyResult<T> {
     Ok(original_result?)
-}
\ No newline at end of file
+}
+
+/// Deterministic pseudo-random numbers for tests, from a 64-bit linear
+/// congruential generator seeded with the wrapped value.
+#[cfg(test)]
+pub struct TestRng(pub u64);
+
+#[cfg(test)]
+impl TestRng {
+    /// The whole 64-bit state, for draws that need every bit.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0
+    }
+
+    /// A number in `0..bound`, taken from the high bits, which are the most random.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        (self.next_u64() >> 33) % bound
+    }
+
+    pub fn next_bool(&mut self) -> bool {
+        self.next_u64() >> 63 == 1
+    }
+}
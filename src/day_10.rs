@@ -1,9 +1,46 @@
 use crate::MyResult;
 use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::{BufRead, Result as IOResult};
 
+#[derive(Clone, Copy, Debug)]
+pub struct BracketPair {
+    pub opening: u8,
+    pub closing: u8,
+    pub error_score: i32,
+    pub completion_score: i64,
+}
+
+pub const DEFAULT_PAIRS: [BracketPair; 4] = [
+    BracketPair {
+        opening: b'(',
+        closing: b')',
+        error_score: 3,
+        completion_score: 1,
+    },
+    BracketPair {
+        opening: b'[',
+        closing: b']',
+        error_score: 57,
+        completion_score: 2,
+    },
+    BracketPair {
+        opening: b'{',
+        closing: b'}',
+        error_score: 1197,
+        completion_score: 3,
+    },
+    BracketPair {
+        opening: b'<',
+        closing: b'>',
+        error_score: 25137,
+        completion_score: 4,
+    },
+];
+
 #[derive(Clone, Copy)]
 struct ClosingInfo {
     closing_char: u8,
@@ -17,92 +54,266 @@ enum CharType {
     Closing { error_score: i32 },
 }
 
-enum LineResult {
-    Corrupted(i32),
-    Incomplete(i64),
+#[derive(Debug)]
+pub enum SyntaxError {
+    DuplicateBracket(char),
+    UnknownChar { column: usize, found: char },
+    CompletionScoreOverflow,
 }
 
-const CHAR_MAP: [CharType; 256] = {
-    let mut cm = [CharType::Other; 256];
-    cm[b'(' as usize] = CharType::Opening(ClosingInfo {
-        closing_char: b')',
-        completion_score: 1,
-    });
-    cm[b')' as usize] = CharType::Closing { error_score: 3 };
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
 
-    cm[b'[' as usize] = CharType::Opening(ClosingInfo {
-        closing_char: b']',
-        completion_score: 2,
-    });
-    cm[b']' as usize] = CharType::Closing { error_score: 57 };
+impl Error for SyntaxError {}
 
-    cm[b'{' as usize] = CharType::Opening(ClosingInfo {
-        closing_char: b'}',
-        completion_score: 3,
-    });
-    cm[b'}' as usize] = CharType::Closing { error_score: 1197 };
+/// Where a corrupted line went wrong. `column` is 1-based; `expected` is
+/// `None` when the line closes more brackets than it opened.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Diagnostic {
+    pub column: usize,
+    pub expected: Option<char>,
+    pub found: char,
+}
 
-    cm[b'<' as usize] = CharType::Opening(ClosingInfo {
-        closing_char: b'>',
-        completion_score: 4,
-    });
-    cm[b'>' as usize] = CharType::Closing { error_score: 25137 };
-    cm
-};
-
-fn score_line(line: &str) -> LineResult {
-    let mut opened_stack = Vec::<ClosingInfo>::new();
-    for b_char in line.bytes() {
-        match CHAR_MAP[b_char as usize] {
-            CharType::Other => panic!(),
-            CharType::Opening(ci) => opened_stack.push(ci),
-            CharType::Closing { error_score } => {
-                let top_of_stack = opened_stack.pop().unwrap();
-                if b_char != top_of_stack.closing_char {
-                    return LineResult::Corrupted(error_score);
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "column {}: expected '{}', found '{}'",
+                self.column, expected, self.found
+            ),
+            None => write!(
+                f,
+                "column {}: expected end of line, found '{}'",
+                self.column, self.found
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LineResult {
+    Corrupted {
+        diagnostic: Diagnostic,
+        error_score: i32,
+    },
+    Incomplete {
+        completion: String,
+        completion_score: i64,
+    },
+}
+
+pub struct Grammar {
+    char_map: [CharType; 256],
+}
+
+impl Grammar {
+    pub fn new(pairs: &[BracketPair]) -> Result<Grammar, SyntaxError> {
+        let mut char_map = [CharType::Other; 256];
+        for pair in pairs {
+            for c in [pair.opening, pair.closing] {
+                if !matches!(char_map[c as usize], CharType::Other) || pair.opening == pair.closing
+                {
+                    return Err(SyntaxError::DuplicateBracket(c as char));
                 }
             }
+            char_map[pair.opening as usize] = CharType::Opening(ClosingInfo {
+                closing_char: pair.closing,
+                completion_score: pair.completion_score,
+            });
+            char_map[pair.closing as usize] = CharType::Closing {
+                error_score: pair.error_score,
+            };
         }
+        Ok(Grammar { char_map })
     }
-    opened_stack.reverse();
-    let mut total_completion_score: i64 = 0;
-    for ClosingInfo {
-        completion_score, ..
-    } in opened_stack
-    {
-        total_completion_score = total_completion_score
-            .checked_mul(5)
-            .unwrap()
-            .checked_add(completion_score)
-            .unwrap();
+
+    pub fn check_line(&self, line: &str) -> Result<LineResult, SyntaxError> {
+        let mut opened_stack = Vec::<ClosingInfo>::new();
+        for (i, c) in line.chars().enumerate() {
+            // Brackets are single bytes, read as the first 256 code points.
+            let char_type = u8::try_from(c).map_or(CharType::Other, |b| self.char_map[b as usize]);
+            match char_type {
+                CharType::Other => {
+                    return Err(SyntaxError::UnknownChar {
+                        column: i + 1,
+                        found: c,
+                    })
+                }
+                CharType::Opening(ci) => opened_stack.push(ci),
+                CharType::Closing { error_score } => {
+                    let expected = opened_stack.pop().map(|ci| char::from(ci.closing_char));
+                    if expected != Some(c) {
+                        return Ok(LineResult::Corrupted {
+                            diagnostic: Diagnostic {
+                                column: i + 1,
+                                expected,
+                                found: c,
+                            },
+                            error_score,
+                        });
+                    }
+                }
+            }
+        }
+        let mut completion = String::with_capacity(opened_stack.len());
+        let mut completion_score: i64 = 0;
+        for ClosingInfo {
+            closing_char,
+            completion_score: char_score,
+        } in opened_stack.into_iter().rev()
+        {
+            completion.push(closing_char as char);
+            completion_score = completion_score
+                .checked_mul(5)
+                .and_then(|x| x.checked_add(char_score))
+                .ok_or(SyntaxError::CompletionScoreOverflow)?;
+        }
+
+        Ok(LineResult::Incomplete {
+            completion,
+            completion_score,
+        })
     }
+}
 
-    return LineResult::Incomplete(total_completion_score);
+impl Default for Grammar {
+    fn default() -> Grammar {
+        Grammar::new(&DEFAULT_PAIRS).unwrap()
+    }
 }
 
 pub fn run_me() -> MyResult<()> {
     let lines: Vec<String> = BufReader::new(File::open(args().nth(1).unwrap())?)
         .lines()
         .collect::<IOResult<Vec<String>>>()?;
-    let task_1_scores: i32 = lines
+    let grammar = Grammar::default();
+    let results = lines
+        .iter()
+        .map(|x| grammar.check_line(x))
+        .collect::<Result<Vec<_>, _>>()?;
+    let task_1_scores: i32 = results
         .iter()
-        .filter_map(|x| match score_line(x) {
-            LineResult::Corrupted(error_score) => Some(error_score),
+        .filter_map(|x| match x {
+            LineResult::Corrupted { error_score, .. } => Some(error_score),
             _ => None,
         })
         .sum();
 
     println!("Task 1: {}", task_1_scores);
 
-    let mut task_2_scores: Vec<i64> = lines
+    let mut task_2_scores: Vec<i64> = results
         .iter()
-        .filter_map(|x| match score_line(x) {
-            LineResult::Incomplete(error_score) => Some(error_score),
+        .filter_map(|x| match x {
+            LineResult::Incomplete {
+                completion_score, ..
+            } => Some(*completion_score),
             _ => None,
         })
         .collect();
-    task_2_scores.sort();
+    task_2_scores.sort_unstable();
     println!("Task 2: {}", task_2_scores[task_2_scores.len() / 2]);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrupted() -> MyResult<()> {
+        let result = Grammar::default().check_line("{([(<{}[<>[]}>{[]{[(<()>")?;
+        assert_eq!(
+            result,
+            LineResult::Corrupted {
+                diagnostic: Diagnostic {
+                    column: 13,
+                    expected: Some(']'),
+                    found: '}'
+                },
+                error_score: 1197
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_over_closed() -> MyResult<()> {
+        let result = Grammar::default().check_line("()]")?;
+        assert_eq!(
+            result,
+            LineResult::Corrupted {
+                diagnostic: Diagnostic {
+                    column: 3,
+                    expected: None,
+                    found: ']'
+                },
+                error_score: 57
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_incomplete() -> MyResult<()> {
+        let result = Grammar::default().check_line("[({(<(())[]>[[{[]{<()<>>")?;
+        assert_eq!(
+            result,
+            LineResult::Incomplete {
+                completion: String::from("}}]])})]"),
+                completion_score: 288957
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_grammar() -> MyResult<()> {
+        let grammar = Grammar::new(&[BracketPair {
+            opening: b'/',
+            closing: b'\\',
+            error_score: 7,
+            completion_score: 2,
+        }])?;
+        assert_eq!(
+            grammar.check_line("//")?,
+            LineResult::Incomplete {
+                completion: String::from("\\\\"),
+                completion_score: 12
+            }
+        );
+        assert!(matches!(
+            grammar.check_line("/)"),
+            Err(SyntaxError::UnknownChar {
+                column: 2,
+                found: ')'
+            })
+        ));
+        assert!(Grammar::new(&[DEFAULT_PAIRS[0], DEFAULT_PAIRS[0]]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_ascii() -> MyResult<()> {
+        assert!(matches!(
+            Grammar::default().check_line("(éé]"),
+            Err(SyntaxError::UnknownChar {
+                column: 2,
+                found: 'é'
+            })
+        ));
+        assert!(matches!(
+            Grammar::default().check_line("(€"),
+            Err(SyntaxError::UnknownChar {
+                column: 2,
+                found: '€'
+            })
+        ));
+        Ok(())
+    }
+}
@@ -1,14 +1,16 @@
+use crate::common::GeneralError;
 use crate::MyResult;
 use ndarray::stack;
 use ndarray::Array1;
 use ndarray::Array2;
 use ndarray::Axis;
+use std::collections::HashMap;
 use std::env::args;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 
-const ADJ_DELTA: &[(i32, i32)] = &[
+pub const MOORE: &[(i32, i32)] = &[
     (-1, -1),
     (-1, 0),
     (-1, 1),
@@ -19,79 +21,278 @@ const ADJ_DELTA: &[(i32, i32)] = &[
     (1, 1),
 ];
 
-fn step(energy_levels: &mut Array2<i32>) -> i32 {
-    *energy_levels += 1;
-    let mut flash_locations: Vec<(i32, i32)> = energy_levels
-        .indexed_iter()
-        .filter_map(|((y, x), &a)| {
-            if a > 9 {
-                Some((y as i32, x as i32))
-            } else {
-                None
-            }
-        })
-        .collect();
-    while let Some((fy, fx)) = flash_locations.pop() {
-        for (dy, dx) in ADJ_DELTA {
-            let y = fy + dy;
-            let x = fx + dx;
-            if !(0..energy_levels.shape()[0] as i32).contains(&y)
-                || !(0..energy_levels.shape()[1] as i32).contains(&x)
-                || energy_levels[(y as usize, x as usize)] > 9
-            {
-                continue;
+pub const VON_NEUMANN: &[(i32, i32)] = &[(-1, 0), (0, -1), (0, 1), (1, 0)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    Bounded,
+    Toroidal,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub flash_threshold: i32,
+    pub neighbourhood: Vec<(i32, i32)>,
+    pub topology: Topology,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> SimulationConfig {
+        SimulationConfig {
+            flash_threshold: 9,
+            neighbourhood: MOORE.to_vec(),
+            topology: Topology::Bounded,
+        }
+    }
+}
+
+impl SimulationConfig {
+    fn neighbour(&self, shape: (i32, i32), (y, x): (i32, i32)) -> Option<(usize, usize)> {
+        match self.topology {
+            Topology::Bounded => {
+                if (0..shape.0).contains(&y) && (0..shape.1).contains(&x) {
+                    Some((y as usize, x as usize))
+                } else {
+                    None
+                }
             }
-            let c = &mut energy_levels[(y as usize, x as usize)];
-            *c += 1;
-            if *c > 9 {
-                flash_locations.push((y, x));
+            Topology::Toroidal => Some((
+                y.rem_euclid(shape.0) as usize,
+                x.rem_euclid(shape.1) as usize,
+            )),
+        }
+    }
+
+    fn step(&self, energy_levels: &mut Array2<i32>) -> u64 {
+        let shape = (
+            energy_levels.shape()[0] as i32,
+            energy_levels.shape()[1] as i32,
+        );
+        *energy_levels += 1;
+        let mut flash_locations: Vec<(i32, i32)> = energy_levels
+            .indexed_iter()
+            .filter_map(|((y, x), &a)| {
+                if a > self.flash_threshold {
+                    Some((y as i32, x as i32))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        while let Some((fy, fx)) = flash_locations.pop() {
+            for (dy, dx) in &self.neighbourhood {
+                let (y, x) = match self.neighbour(shape, (fy + dy, fx + dx)) {
+                    Some(yx) => yx,
+                    None => continue,
+                };
+                let c = &mut energy_levels[(y, x)];
+                if *c > self.flash_threshold {
+                    continue;
+                }
+                *c += 1;
+                if *c > self.flash_threshold {
+                    flash_locations.push((y as i32, x as i32));
+                }
             }
         }
+
+        energy_levels
+            .iter_mut()
+            .map(|x| {
+                if *x > self.flash_threshold {
+                    *x = 0;
+                    1
+                } else {
+                    0
+                }
+            })
+            .sum()
+    }
+}
+
+/// Steps the grid lazily and remembers every state it has seen, so that once
+/// the evolution repeats, queries about any later step are answered from the
+/// cycle instead of by simulating.
+pub struct Simulation {
+    config: SimulationConfig,
+    energy_levels: Array2<i32>,
+    seen_at: HashMap<Array2<i32>, usize>,
+    cumulative_flashes: Vec<u64>,
+    cycle: Option<(usize, usize)>,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig, energy_levels: Array2<i32>) -> Simulation {
+        Simulation {
+            config,
+            seen_at: HashMap::from([(energy_levels.clone(), 0)]),
+            energy_levels,
+            cumulative_flashes: vec![0],
+            cycle: None,
+        }
+    }
+
+    fn simulated_steps(&self) -> usize {
+        self.cumulative_flashes.len() - 1
+    }
+
+    fn advance(&mut self) {
+        let flashes = self.config.step(&mut self.energy_levels);
+        let total = self.cumulative_flashes.last().unwrap() + flashes;
+        self.cumulative_flashes.push(total);
+        let current_step = self.simulated_steps();
+        if let Some(&previous_step) = self.seen_at.get(&self.energy_levels) {
+            self.cycle = Some((previous_step, current_step - previous_step));
+        } else {
+            self.seen_at
+                .insert(self.energy_levels.clone(), current_step);
+        }
+    }
+
+    /// Returns `(start, period)` such that the state after `start + period`
+    /// steps equals the state after `start` steps, simulating until found.
+    pub fn find_cycle(&mut self) -> (usize, usize) {
+        while self.cycle.is_none() {
+            self.advance();
+        }
+        self.cycle.unwrap()
+    }
+
+    pub fn flashes_after(&mut self, n_steps: u64) -> u64 {
+        while self.cycle.is_none() && (self.simulated_steps() as u64) < n_steps {
+            self.advance();
+        }
+        if n_steps <= self.simulated_steps() as u64 {
+            return self.cumulative_flashes[n_steps as usize];
+        }
+        let (start, period) = self.cycle.unwrap();
+        let flashes_per_cycle =
+            self.cumulative_flashes[start + period] - self.cumulative_flashes[start];
+        let after_start = n_steps - start as u64;
+        let full_cycles = after_start / period as u64;
+        let remainder = (after_start % period as u64) as usize;
+        self.cumulative_flashes[start + remainder] + full_cycles * flashes_per_cycle
     }
 
-    let total_flashes = energy_levels
-        .iter_mut()
-        .map(|x| {
-            if *x > 9 {
-                *x = 0;
-                1
-            } else {
-                0
+    /// First step after which every octopus has flashed, or `None` if the
+    /// evolution cycles without ever synchronising.
+    pub fn first_synchronised_step(&mut self) -> Option<u64> {
+        let cell_count = self.energy_levels.len() as u64;
+        let mut step_no = 1;
+        loop {
+            if step_no > self.simulated_steps() {
+                if self.cycle.is_some() {
+                    return None;
+                }
+                self.advance();
             }
-        })
-        .sum();
-    total_flashes
+            if self.cumulative_flashes[step_no] - self.cumulative_flashes[step_no - 1] == cell_count
+            {
+                return Some(step_no as u64);
+            }
+            step_no += 1;
+        }
+    }
 }
 
-pub fn run_me() -> MyResult<()> {
-    let energy_levels_rows = BufReader::new(File::open(args().nth(1).unwrap())?)
+fn read_energy_levels(reader: impl BufRead) -> MyResult<Array2<i32>> {
+    let energy_levels_rows = reader
         .lines()
         .map(|line| {
-            line.unwrap()
+            line?
                 .chars()
-                .map(|c| c as i32 - '0' as i32)
-                .collect::<Array1<i32>>()
+                .map(|c| {
+                    c.to_digit(10)
+                        .map(|d| d as i32)
+                        .ok_or_else(|| GeneralError(format!("Not a digit {}", c)).into())
+                })
+                .collect::<MyResult<Array1<i32>>>()
         })
-        .collect::<Vec<Array1<i32>>>();
-    let initial_energy_levels = stack(
+        .collect::<MyResult<Vec<Array1<i32>>>>()?;
+    let width = energy_levels_rows.first().map_or(0, Array1::len);
+    if let Some(row_no) = energy_levels_rows.iter().position(|x| x.len() != width) {
+        return Err(Box::new(GeneralError(format!(
+            "Row {} has length {}, expected {}",
+            row_no,
+            energy_levels_rows[row_no].len(),
+            width
+        ))));
+    }
+    Ok(stack(
         Axis(0),
         &energy_levels_rows
             .iter()
             .map(Array1::view)
             .collect::<Vec<_>>(),
-    )?;
-    let mut energy_levels = initial_energy_levels.clone();
-    let mut total_flashes = 0;
-    for _ in 0..100 {
-        total_flashes += step(&mut energy_levels);
-    }
-    println!("Task 1: {}", total_flashes);
-
-    let mut energy_levels = initial_energy_levels.clone();
-    let first_sync_step = (1..1_000).find(|step_no| {
-        step(&mut energy_levels);
-        energy_levels.iter().all(|&x| x == 0)
-    });
-    println!("Task 2: {:?}", first_sync_step);
+    )?)
+}
+
+pub fn run_me() -> MyResult<()> {
+    let initial_energy_levels =
+        read_energy_levels(BufReader::new(File::open(args().nth(1).unwrap())?))?;
+    let mut simulation = Simulation::new(SimulationConfig::default(), initial_energy_levels);
+    println!("Task 1: {}", simulation.flashes_after(100));
+
+    match simulation.first_synchronised_step() {
+        Some(step_no) => println!("Task 2: {}", step_no),
+        None => println!("Task 2: never synchronises"),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
+
+    fn example() -> MyResult<Array2<i32>> {
+        read_energy_levels(
+            &b"5483143223\n2745854711\n5264556173\n6141336146\n6357385478\n\
+               4167524645\n2176841721\n6882881134\n4846848554\n5283751526"[..],
+        )
+    }
+
+    #[test]
+    fn test_example() -> MyResult<()> {
+        let mut simulation = Simulation::new(SimulationConfig::default(), example()?);
+        assert_eq!(simulation.flashes_after(10), 204);
+        assert_eq!(simulation.flashes_after(100), 1656);
+        assert_eq!(simulation.first_synchronised_step(), Some(195));
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_horizon() -> MyResult<()> {
+        let mut simulation = Simulation::new(SimulationConfig::default(), example()?);
+        let (start, period) = simulation.find_cycle();
+        assert_eq!(period, 10);
+        let flashes_at_start = simulation.flashes_after(start as u64);
+        // Synchronised: one full flash of all 100 octopuses per 10 steps.
+        assert_eq!(
+            simulation.flashes_after(start as u64 + 10 * 3_000_000_000),
+            flashes_at_start + 100 * 3_000_000_000
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_toroidal() -> MyResult<()> {
+        let config = SimulationConfig {
+            topology: Topology::Toroidal,
+            ..SimulationConfig::default()
+        };
+        let mut simulation = Simulation::new(config, read_energy_levels(&b"900\n000\n000"[..])?);
+        // The flash wraps around to reach every other cell.
+        assert_eq!(simulation.flashes_after(1), 1);
+        assert_eq!(
+            simulation.energy_levels,
+            arr2(&[[0, 2, 2], [2, 2, 2], [2, 2, 2]])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ragged() {
+        assert!(read_energy_levels(&b"123\n12"[..]).is_err());
+    }
+}
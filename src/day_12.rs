@@ -1,74 +1,398 @@
 use crate::MyResult;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-fn parse_line(line: &str) -> (String, String) {
-    let (a, b) = line.split_once('-').unwrap();
-    return (String::from(a), String::from(b));
+#[derive(Debug)]
+pub enum CaveError {
+    BadLine(String),
+    MissingCave(&'static str),
+    UnknownCave(String),
+    TooManySmallCaves(usize),
+    InfiniteLoop(String, String),
 }
 
-struct State {
-    current_node: String,
-    visited_nodes: HashSet<String>,
-    visited_twice: Option<String>,
+impl Display for CaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
 }
 
-fn count_visits(neighbors: &HashMap<String, Vec<String>>, allow_visited_twice: bool) -> i32 {
-    let mut stack = vec![State {
-        current_node: String::from("start"),
-        visited_nodes: HashSet::from([String::from("start")]),
-        visited_twice: None,
-    }];
-    let mut num_paths = 0i32;
-
-    while let Some(State {
-        current_node,
-        visited_nodes,
-        visited_twice,
-    }) = stack.pop()
-    {
-        for next_node in &neighbors[&current_node] {
-            let is_next_small = next_node.chars().next().unwrap().is_lowercase();
-            let mut new_visited_twice = visited_twice.clone();
-            if next_node == "end" {
-                num_paths += 1;
+impl Error for CaveError {}
+
+fn parse_line(line: &str) -> Result<(&str, &str), CaveError> {
+    line.split_once('-')
+        .ok_or_else(|| CaveError::BadLine(String::from(line)))
+}
+
+/// Caves interned to dense ids. Small caves additionally get a bit in the
+/// visited mask.
+pub struct CaveGraph {
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+    small_bit: Vec<Option<u64>>,
+    neighbors: Vec<Vec<usize>>,
+    start: usize,
+    end: usize,
+}
+
+impl CaveGraph {
+    pub fn from_edges<'a>(
+        edges: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<CaveGraph, CaveError> {
+        let mut ids = HashMap::<String, usize>::new();
+        let mut names = Vec::new();
+        let mut neighbors = Vec::<Vec<usize>>::new();
+        let mut intern = |name: &str| {
+            *ids.entry(String::from(name)).or_insert_with(|| {
+                names.push(String::from(name));
+                neighbors.push(Vec::new());
+                names.len() - 1
+            })
+        };
+        let edges = edges
+            .into_iter()
+            .map(|(a, b)| (intern(a), intern(b)))
+            .collect::<Vec<_>>();
+        let start = *ids.get("start").ok_or(CaveError::MissingCave("start"))?;
+        let end = *ids.get("end").ok_or(CaveError::MissingCave("end"))?;
+
+        let small_count = names.iter().filter(|x| is_small(x)).count();
+        if small_count > u64::BITS as usize {
+            return Err(CaveError::TooManySmallCaves(small_count));
+        }
+        let mut next_bit = 1u64;
+        let small_bit = names
+            .iter()
+            .map(|name| {
+                is_small(name).then(|| {
+                    let bit = next_bit;
+                    next_bit = next_bit.wrapping_shl(1);
+                    bit
+                })
+            })
+            .collect();
+        for (a, b) in edges {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+        }
+        Ok(CaveGraph {
+            ids,
+            names,
+            small_bit,
+            neighbors,
+            start,
+            end,
+        })
+    }
+
+    /// Two adjacent big caves (or a big cave linked to itself) can be
+    /// bounced between forever, so there would be infinitely many paths.
+    fn check_finite(&self) -> Result<(), CaveError> {
+        for (a, neighbors) in self.neighbors.iter().enumerate() {
+            for &b in neighbors {
+                if self.small_bit[a].is_none() && self.small_bit[b].is_none() {
+                    return Err(CaveError::InfiniteLoop(
+                        self.names[a].clone(),
+                        self.names[b].clone(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts start→end paths where small caves may be entered again at most
+    /// `max_revisits` times in total; `start` is never re-entered.
+    pub fn count_paths(&self, max_revisits: u32) -> Result<u64, CaveError> {
+        self.check_finite()?;
+        let mut memo = HashMap::new();
+        let start_mask = self.small_bit[self.start].unwrap_or(0);
+        Ok(self.count_from(self.start, start_mask, max_revisits, &mut memo))
+    }
+
+    fn cave_id(&self, name: &str) -> Result<usize, CaveError> {
+        self.ids
+            .get(name)
+            .copied()
+            .ok_or_else(|| CaveError::UnknownCave(String::from(name)))
+    }
+
+    /// Lazily enumerates the paths counted by `count_paths` in depth-first
+    /// order.
+    pub fn paths(&self, max_revisits: u32) -> Result<Paths<'_>, CaveError> {
+        self.check_finite()?;
+        Ok(Paths {
+            graph: self,
+            stack: vec![Frame {
+                node: self.start,
+                next_index: 0,
+                visited_mask: self.small_bit[self.start].unwrap_or(0),
+                budget: max_revisits,
+            }],
+            must_visit: Vec::new(),
+            max_length: None,
+        })
+    }
+
+    /// Renders the graph in Graphviz DOT, labelling every cave and tunnel with
+    /// how many times `paths` pass through it.
+    pub fn to_dot<'a>(
+        &self,
+        paths: impl IntoIterator<Item = Vec<&'a str>>,
+    ) -> Result<String, CaveError> {
+        let mut node_visits = vec![0u64; self.names.len()];
+        let mut edge_visits = HashMap::<(usize, usize), u64>::new();
+        for path in paths {
+            let ids = path
+                .into_iter()
+                .map(|x| self.cave_id(x))
+                .collect::<Result<Vec<_>, _>>()?;
+            for &id in &ids {
+                node_visits[id] += 1;
+            }
+            for (&a, &b) in ids.iter().zip(ids.iter().skip(1)) {
+                *edge_visits.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+
+        let mut dot = String::from("graph caves {\n");
+        for (id, name) in self.names.iter().enumerate() {
+            let shape = if self.small_bit[id].is_some() {
+                "ellipse"
+            } else {
+                "box"
+            };
+            dot += &format!(
+                "    \"{}\" [shape={}, label=\"{}\\n{}\"];\n",
+                name, shape, name, node_visits[id]
+            );
+        }
+        for (a, neighbors) in self.neighbors.iter().enumerate() {
+            for &b in neighbors.iter().filter(|&&b| a <= b) {
+                dot += &format!(
+                    "    \"{}\" -- \"{}\" [label=\"{}\"];\n",
+                    self.names[a],
+                    self.names[b],
+                    edge_visits.get(&(a, b)).unwrap_or(&0)
+                );
+            }
+        }
+        dot += "}\n";
+        Ok(dot)
+    }
+
+    fn count_from(
+        &self,
+        node: usize,
+        visited_mask: u64,
+        budget: u32,
+        memo: &mut HashMap<(usize, u64, u32), u64>,
+    ) -> u64 {
+        if let Some(&count) = memo.get(&(node, visited_mask, budget)) {
+            return count;
+        }
+        let mut count = 0;
+        for &next_node in &self.neighbors[node] {
+            if next_node == self.start {
                 continue;
-            } else if is_next_small && visited_nodes.contains(next_node) {
-                if allow_visited_twice && visited_twice.is_none() && next_node != "start" {
-                    new_visited_twice = Some(next_node.clone());
-                } else {
-                    continue;
+            }
+            if next_node == self.end {
+                count += 1;
+                continue;
+            }
+            count += match self.small_bit[next_node] {
+                None => self.count_from(next_node, visited_mask, budget, memo),
+                Some(bit) if visited_mask & bit == 0 => {
+                    self.count_from(next_node, visited_mask | bit, budget, memo)
+                }
+                Some(..) if budget > 0 => {
+                    self.count_from(next_node, visited_mask, budget - 1, memo)
                 }
+                Some(..) => 0,
+            };
+        }
+        memo.insert((node, visited_mask, budget), count);
+        count
+    }
+}
+
+struct Frame {
+    node: usize,
+    next_index: usize,
+    visited_mask: u64,
+    budget: u32,
+}
+
+/// Iterator returned by `CaveGraph::paths`. The stack holds the caves of the
+/// current partial path together with the next neighbor to try from each.
+pub struct Paths<'a> {
+    graph: &'a CaveGraph,
+    stack: Vec<Frame>,
+    must_visit: Vec<usize>,
+    max_length: Option<usize>,
+}
+
+impl<'a> Paths<'a> {
+    /// Only keeps paths that enter `cave` at least once.
+    pub fn through(mut self, cave: &str) -> Result<Paths<'a>, CaveError> {
+        self.must_visit.push(self.graph.cave_id(cave)?);
+        Ok(self)
+    }
+
+    /// Only keeps paths of at most `max_length` tunnels.
+    pub fn max_length(mut self, max_length: usize) -> Paths<'a> {
+        self.max_length = Some(max_length);
+        self
+    }
+}
+
+impl<'a> Iterator for Paths<'a> {
+    type Item = Vec<&'a str>;
+
+    fn next(&mut self) -> Option<Vec<&'a str>> {
+        let graph = self.graph;
+        loop {
+            let path_length = self.stack.len();
+            let top = self.stack.last_mut()?;
+            let Some(&next_node) = graph.neighbors[top.node].get(top.next_index) else {
+                self.stack.pop();
+                continue;
+            };
+            top.next_index += 1;
+            let (visited_mask, budget) = (top.visited_mask, top.budget);
+            if next_node == graph.start || self.max_length.is_some_and(|x| path_length > x) {
+                continue;
             }
-            let mut new_visited = visited_nodes.clone();
-            if is_next_small {
-                new_visited.insert(next_node.clone());
+            if next_node == graph.end {
+                let passes_filters = self
+                    .must_visit
+                    .iter()
+                    .all(|&cave| self.stack.iter().any(|frame| frame.node == cave));
+                if passes_filters {
+                    let mut path = self
+                        .stack
+                        .iter()
+                        .map(|frame| graph.names[frame.node].as_str())
+                        .collect::<Vec<_>>();
+                    path.push(graph.names[next_node].as_str());
+                    return Some(path);
+                }
+                continue;
             }
-            stack.push(State {
-                current_node: next_node.clone(),
-                visited_nodes: new_visited,
-                visited_twice: new_visited_twice,
+            let (visited_mask, budget) = match graph.small_bit[next_node] {
+                None => (visited_mask, budget),
+                Some(bit) if visited_mask & bit == 0 => (visited_mask | bit, budget),
+                Some(..) if budget > 0 => (visited_mask, budget - 1),
+                Some(..) => continue,
+            };
+            self.stack.push(Frame {
+                node: next_node,
+                next_index: 0,
+                visited_mask,
+                budget,
             });
         }
     }
-    return num_paths;
+}
+
+fn is_small(name: &str) -> bool {
+    name.chars().next().is_some_and(char::is_lowercase)
 }
 
 pub fn run_me() -> MyResult<()> {
-    let mut neighbors: HashMap<String, Vec<String>> = HashMap::new();
-    BufReader::new(File::open(args().nth(1).unwrap())?)
+    let lines = BufReader::new(File::open(args().nth(1).unwrap())?)
         .lines()
-        .map(|x| parse_line(&x.unwrap()))
-        .for_each(|(a, b)| {
-            for (x, y) in [(&a, &b), (&b, &a)] {
-                neighbors.entry(x.clone()).or_default().push(y.clone());
-            }
-        });
-    // println!("{:?}", neighbors);
+        .collect::<Result<Vec<_>, _>>()?;
+    let graph = CaveGraph::from_edges(
+        lines
+            .iter()
+            .map(|x| parse_line(x))
+            .collect::<Result<Vec<_>, _>>()?,
+    )?;
 
-    println!("Task 1: {}", count_visits(&neighbors, false));
-    println!("Task 2: {}", count_visits(&neighbors, true));
+    println!("Task 1: {}", graph.count_paths(0)?);
+    println!("Task 2: {}", graph.count_paths(1)?);
+    if args().nth(2).as_deref() == Some("--dot") {
+        let path = args().nth(3).ok_or("--dot needs an output file")?;
+        std::fs::write(&path, graph.to_dot(graph.paths(1)?)?)?;
+        println!("Wrote {}", path);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(input: &str) -> Result<CaveGraph, CaveError> {
+        CaveGraph::from_edges(input.split_whitespace().map(|x| parse_line(x).unwrap()))
+    }
+
+    #[test]
+    fn test_count_paths() -> MyResult<()> {
+        let small = graph("start-A start-b A-c A-b b-d A-end b-end")?;
+        assert_eq!(small.count_paths(0)?, 10);
+        assert_eq!(small.count_paths(1)?, 36);
+        let larger = graph(
+            "fs-end he-DX fs-he start-DX pj-DX end-zg zg-sl zg-pj pj-he RW-he fs-DX \
+             pj-RW zg-RW start-pj he-WI zg-he pj-fs start-RW",
+        )?;
+        assert_eq!(larger.count_paths(0)?, 226);
+        assert_eq!(larger.count_paths(1)?, 3509);
+        Ok(())
+    }
+
+    #[test]
+    fn test_more_revisits() -> MyResult<()> {
+        // start-a-end with a loop through A: a may be entered 1 + k times.
+        let g = graph("start-a a-A a-end")?;
+        assert_eq!(g.count_paths(0)?, 1);
+        assert_eq!(g.count_paths(1)?, 2);
+        assert_eq!(g.count_paths(5)?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths() -> MyResult<()> {
+        let g = graph("start-A start-b A-c A-b b-d A-end b-end")?;
+        assert_eq!(g.paths(0)?.count(), 10);
+        assert_eq!(g.paths(1)?.count(), 36);
+        let mut through_c = g.paths(0)?.through("c")?.max_length(5).collect::<Vec<_>>();
+        through_c.sort();
+        assert_eq!(
+            through_c,
+            vec![
+                vec!["start", "A", "c", "A", "b", "end"],
+                vec!["start", "A", "c", "A", "end"],
+                vec!["start", "b", "A", "c", "A", "end"],
+            ]
+        );
+        assert!(g.paths(0)?.through("x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_dot() -> MyResult<()> {
+        let g = graph("start-a a-end")?;
+        assert_eq!(
+            g.to_dot(g.paths(0)?)?,
+            "graph caves {\n    \"start\" [shape=ellipse, label=\"start\\n1\"];\n    \
+             \"a\" [shape=ellipse, label=\"a\\n1\"];\n    \
+             \"end\" [shape=ellipse, label=\"end\\n1\"];\n    \
+             \"start\" -- \"a\" [label=\"1\"];\n    \
+             \"a\" -- \"end\" [label=\"1\"];\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_infinite_loop() -> MyResult<()> {
+        let g = graph("start-A A-B B-end")?;
+        assert!(matches!(g.count_paths(0), Err(CaveError::InfiniteLoop(..))));
+        Ok(())
+    }
+}
@@ -1,32 +1,104 @@
 use crate::{common::GeneralError, MyResult};
-use ndarray::arr1;
 use ndarray::Array2;
 use ndarray::Axis;
-use ndarray::Slice;
-use ndarray::{s, stack};
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::io::BufRead;
 use std::str::FromStr;
 
-struct Fold {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fold {
     axis: i32,
     place: i32,
 }
 
-fn compute_dots_after_folds(mut dot_coords: Array2<i32>, folds: &[Fold]) -> Array2<i32> {
-    for Fold { axis, place } in folds {
-        for x in dot_coords.index_axis_mut(Axis(1), *axis as usize) {
-            if *x > *place {
-                *x = place - (*x - place);
-            }
+impl Fold {
+    fn apply(&self, mut dot: [i32; 2]) -> [i32; 2] {
+        let x = &mut dot[self.axis as usize];
+        if *x > self.place {
+            *x = self.place - (*x - self.place);
         }
+        dot
+    }
+}
+
+/// The sheet of dots after some folds. Overlapping dots are merged as soon as
+/// they land on the same cell, but each cell remembers which of the original
+/// dots ended up there. A fold past the middle of the paper may leave
+/// coordinates negative.
+pub struct TransparentPaper {
+    origins: HashMap<[i32; 2], Vec<[i32; 2]>>,
+    history: Vec<Fold>,
+}
+
+impl TransparentPaper {
+    pub fn new(dots: impl IntoIterator<Item = [i32; 2]>) -> TransparentPaper {
+        let mut origins = HashMap::<[i32; 2], Vec<[i32; 2]>>::new();
+        for dot in dots {
+            origins.entry(dot).or_default().push(dot);
+        }
+        TransparentPaper {
+            origins,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn fold(&mut self, fold: Fold) {
+        let mut folded = HashMap::<[i32; 2], Vec<[i32; 2]>>::with_capacity(self.origins.len());
+        for (dot, origins) in self.origins.drain() {
+            folded.entry(fold.apply(dot)).or_default().extend(origins);
+        }
+        self.origins = folded;
+        self.history.push(fold);
+    }
+
+    pub fn history(&self) -> &[Fold] {
+        &self.history
+    }
+
+    pub fn dot_count(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn dots(&self) -> impl Iterator<Item = &[i32; 2]> {
+        self.origins.keys()
+    }
+
+    /// The original dots that were folded onto `cell`.
+    pub fn origins(&self, cell: [i32; 2]) -> &[[i32; 2]] {
+        self.origins.get(&cell).map_or(&[], Vec::as_slice)
+    }
+
+    /// Rows of `#` and `.` with the origin in the top left corner, unless
+    /// a fold left dots at negative coordinates.
+    pub fn render(&self) -> MyResult<Vec<String>> {
+        if self.origins.is_empty() {
+            Err(GeneralError(String::from("empty coords")))?
+        }
+        let ([min_x, min_y], [max_x, max_y]) =
+            self.dots()
+                .fold(([0, 0], [i32::MIN, i32::MIN]), |(min, max), dot| {
+                    (
+                        [min[0].min(dot[0]), min[1].min(dot[1])],
+                        [max[0].max(dot[0]), max[1].max(dot[1])],
+                    )
+                });
+        let mut bitmap = Array2::from_elem(
+            ((max_y - min_y + 1) as usize, (max_x - min_x + 1) as usize),
+            b'.',
+        );
+        for dot in self.dots() {
+            bitmap[[(dot[1] - min_y) as usize, (dot[0] - min_x) as usize]] = b'#';
+        }
+        Ok(bitmap
+            .axis_iter(Axis(0))
+            .map(|line| String::from_utf8(line.to_vec()))
+            .collect::<Result<_, _>>()?)
     }
-    dot_coords
 }
 
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let lines = reader.lines();
-    let mut rows = Vec::new();
+    let mut dots = Vec::new();
     let mut reading_dots = true;
     let mut folds = Vec::new();
     for line in lines {
@@ -41,7 +113,7 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
                 .ok_or(GeneralError(format!("Cannot split {}", line)))?;
             let x = i32::from_str(x)?;
             let y = i32::from_str(y)?;
-            rows.push(arr1(&[x, y]));
+            dots.push([x, y]);
         } else {
             let (message, place) = line
                 .split_once("=")
@@ -66,36 +138,87 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
             });
         }
     }
-    let dot_coords = stack(Axis(0), &rows.iter().map(|x| x.view()).collect::<Vec<_>>())?;
-    // println!("{:?}", dot_coords);
-    println!(
-        "Task 1: {}",
-        compute_dots_after_folds(dot_coords.clone(), &folds[..1])
-            .axis_iter(Axis(0))
-            .map(|x| x.to_vec())
-            .collect::<HashSet<Vec<i32>>>()
-            .len()
-    );
-    let folded_dot_coords = compute_dots_after_folds(dot_coords, &folds);
-    let max_x = folded_dot_coords
-        .index_axis(Axis(1), 0)
-        .iter()
-        .max()
-        .ok_or(GeneralError(String::from("empty coords")))?
-        + 1;
-    let max_y = folded_dot_coords
-        .index_axis(Axis(1), 1)
-        .iter()
-        .max()
-        .ok_or(GeneralError(String::from("empty coords")))?
-        + 1;
-    let mut bitmap = Array2::from_elem((max_y as usize, max_x as usize), b'.');
-    for dot in folded_dot_coords.axis_iter(Axis(0)) {
-        bitmap[[dot[1] as usize, dot[0] as usize]] = b'#';
+    let mut paper = TransparentPaper::new(dots);
+    let mut folds = folds.into_iter();
+    if let Some(fold) = folds.next() {
+        paper.fold(fold);
     }
+    println!("Task 1: {}", paper.dot_count());
+    folds.for_each(|fold| paper.fold(fold));
     println!("Task 2");
-    for line in bitmap.axis_iter(Axis(0)) {
-        println!("{}", String::from_utf8(line.to_vec())?);
+    for line in paper.render()? {
+        println!("{}", line);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> TransparentPaper {
+        TransparentPaper::new([
+            [6, 10],
+            [0, 14],
+            [9, 10],
+            [0, 3],
+            [10, 4],
+            [4, 11],
+            [6, 0],
+            [6, 12],
+            [4, 1],
+            [0, 13],
+            [10, 12],
+            [3, 4],
+            [3, 0],
+            [8, 4],
+            [1, 10],
+            [2, 14],
+            [8, 10],
+            [9, 0],
+        ])
+    }
+
+    #[test]
+    fn test_example() -> MyResult<()> {
+        let mut paper = example();
+        paper.fold(Fold { axis: 1, place: 7 });
+        assert_eq!(paper.dot_count(), 17);
+        paper.fold(Fold { axis: 0, place: 5 });
+        assert_eq!(paper.dot_count(), 16);
+        assert_eq!(
+            paper.history(),
+            [Fold { axis: 1, place: 7 }, Fold { axis: 0, place: 5 }]
+        );
+        assert_eq!(
+            paper.render()?,
+            ["#####", "#...#", "#...#", "#...#", "#####"]
+        );
+        let mut origins = paper.origins([2, 4]).to_vec();
+        origins.sort();
+        assert_eq!(origins, [[8, 4], [8, 10]]);
+        assert!(paper.origins([2, 2]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_past_middle() -> MyResult<()> {
+        let mut paper = TransparentPaper::new([[0, 0], [1, 0], [5, 0]]);
+        paper.fold(Fold { axis: 0, place: 4 });
+        paper.fold(Fold { axis: 0, place: 1 });
+        let mut dots = paper.dots().copied().collect::<Vec<_>>();
+        dots.sort();
+        assert_eq!(dots, [[-1, 0], [0, 0], [1, 0]]);
+        assert_eq!(paper.origins([-1, 0]), [[5, 0]]);
+        assert_eq!(paper.render()?, ["###"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_keeps_origin() -> MyResult<()> {
+        let paper = TransparentPaper::new([[1, 1], [2, 1]]);
+        assert_eq!(paper.render()?, ["...", ".##"]);
+        assert!(TransparentPaper::new([]).render().is_err());
+        Ok(())
+    }
+}
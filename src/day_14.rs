@@ -1,101 +1,649 @@
+use crate::common::GeneralError;
 use crate::MyResult;
-use std::collections::HashMap;
+use num_bigint::BigUint;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::Display;
 use std::io::BufRead;
 
 type PolyPair = (u8, u8);
 type CountMap = HashMap<PolyPair, u64>;
 type TransformMap = HashMap<PolyPair, [PolyPair; 2]>;
 
-fn do_step(transform_map: &TransformMap, count_map: CountMap) -> CountMap {
-    let mut result = CountMap::new();
+/// Line numbers are 1-based and count the template and the blank line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuleError {
+    EmptyTemplate,
+    MissingSeparator,
+    Malformed {
+        line_no: usize,
+        line: String,
+    },
+    NonAscii {
+        line_no: usize,
+        element: char,
+    },
+    Duplicate {
+        line_no: usize,
+        first_line_no: usize,
+    },
+    Conflicting {
+        line_no: usize,
+        first_line_no: usize,
+    },
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for RuleError {}
+
+fn check_ascii(line_no: usize, elements: &str) -> Result<(), RuleError> {
+    match elements.chars().find(|c| !c.is_ascii()) {
+        Some(element) => Err(RuleError::NonAscii { line_no, element }),
+        None => Ok(()),
+    }
+}
+
+fn parse_input(reader: impl BufRead) -> MyResult<(Vec<u8>, TransformMap)> {
+    let mut lines = reader.lines();
+    let template = lines.next().ok_or(RuleError::EmptyTemplate)??;
+    check_ascii(1, &template)?;
+    if template.is_empty() {
+        return Err(Box::new(RuleError::EmptyTemplate));
+    }
+    if !lines.next().ok_or(RuleError::MissingSeparator)??.is_empty() {
+        return Err(Box::new(RuleError::MissingSeparator));
+    }
+
+    let mut rules = HashMap::<PolyPair, (u8, usize)>::new();
+    for (line_no, line) in (3..).zip(lines) {
+        let line = line?;
+        check_ascii(line_no, &line)?;
+        let (starting_point, result) = match line.split_once(" -> ") {
+            Some((starting_point, result)) if starting_point.len() == 2 && result.len() == 1 => {
+                (starting_point.as_bytes(), result.as_bytes()[0])
+            }
+            _ => return Err(Box::new(RuleError::Malformed { line_no, line })),
+        };
+        let pair = (starting_point[0], starting_point[1]);
+        if let Some(&(first_result, first_line_no)) = rules.get(&pair) {
+            return Err(Box::new(if first_result == result {
+                RuleError::Duplicate {
+                    line_no,
+                    first_line_no,
+                }
+            } else {
+                RuleError::Conflicting {
+                    line_no,
+                    first_line_no,
+                }
+            }));
+        }
+        rules.insert(pair, (result, line_no));
+    }
+    let transform_map = rules
+        .into_iter()
+        .map(|((a, b), (c, _))| ((a, b), [(a, c), (c, b)]))
+        .collect();
+    Ok((template.into_bytes(), transform_map))
+}
 
-    for (pair, count) in count_map {
-        if let Some([new_pair_1, new_pair_2]) = transform_map.get(&pair) {
-            *result.entry(*new_pair_1).or_default() += count;
-            *result.entry(*new_pair_2).or_default() += count;
-        } else {
-            *result.entry(pair).or_default() += count;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arithmetic {
+    Exact,
+    Modulo(u64),
+}
+
+impl Arithmetic {
+    fn reduce(&self, x: BigUint) -> BigUint {
+        match self {
+            Arithmetic::Exact => x,
+            Arithmetic::Modulo(modulus) => x % *modulus,
         }
     }
-    result
 }
 
-fn compute_char_counts(count_map: CountMap, first_char: u8, last_char: u8) -> [u64; 128] {
-    let mut result = [0; 128];
+type Matrix = Vec<Vec<BigUint>>;
 
-    result[first_char as usize] += 1;
-    result[last_char as usize] += 1;
+/// Pair counts as a vector, advanced by powers of the pair-transition matrix
+/// so that step N costs O(log N) matrix-vector products. `powers[k]` holds
+/// the transition matrix raised to 2^k and is filled in on demand.
+pub struct PolymerGrowth {
+    arithmetic: Arithmetic,
+    pairs: Vec<PolyPair>,
+    initial_counts: Vec<BigUint>,
+    last_char: u8,
+    powers: Vec<Matrix>,
+}
 
-    for ((char_1, char_2), count) in count_map {
-        result[char_1 as usize] += count;
-        result[char_2 as usize] += count;
+impl PolymerGrowth {
+    pub fn new(
+        template: &[u8],
+        transform_map: &TransformMap,
+        arithmetic: Arithmetic,
+    ) -> MyResult<PolymerGrowth> {
+        if arithmetic == Arithmetic::Modulo(0) {
+            return Err(Box::new(GeneralError(String::from(
+                "Modulus must be positive",
+            ))));
+        }
+        let &last_char = template
+            .last()
+            .ok_or_else(|| GeneralError(String::from("Empty template")))?;
+        let elements = template
+            .iter()
+            .chain(
+                transform_map
+                    .values()
+                    .flat_map(|[(a, b), (c, d)]| [a, b, c, d]),
+            )
+            .copied()
+            .collect::<BTreeSet<u8>>();
+        let pairs = elements
+            .iter()
+            .flat_map(|&a| elements.iter().map(move |&b| (a, b)))
+            .collect::<Vec<PolyPair>>();
+        let index = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, &pair)| (pair, i))
+            .collect::<HashMap<PolyPair, usize>>();
+
+        let mut initial_counts = vec![BigUint::default(); pairs.len()];
+        for (&c1, &c2) in template.iter().zip(template.iter().skip(1)) {
+            initial_counts[index[&(c1, c2)]] += 1u32;
+        }
+        let mut transition = vec![vec![BigUint::default(); pairs.len()]; pairs.len()];
+        for (from, pair) in pairs.iter().enumerate() {
+            match transform_map.get(pair) {
+                Some(new_pairs) => {
+                    for new_pair in new_pairs {
+                        transition[index[new_pair]][from] += 1u32;
+                    }
+                }
+                None => transition[from][from] += 1u32,
+            }
+        }
+        Ok(PolymerGrowth {
+            arithmetic,
+            pairs,
+            initial_counts: initial_counts
+                .into_iter()
+                .map(|x| arithmetic.reduce(x))
+                .collect(),
+            last_char,
+            powers: vec![transition
+                .into_iter()
+                .map(|row| row.into_iter().map(|x| arithmetic.reduce(x)).collect())
+                .collect()],
+        })
     }
-    for c in &mut result {
-        assert_eq!(*c % 2, 0);
-        *c /= 2;
+
+    fn multiply(&self, matrix: &Matrix, vector: &[BigUint]) -> Vec<BigUint> {
+        matrix
+            .iter()
+            .map(|row| {
+                self.arithmetic
+                    .reduce(row.iter().zip(vector).map(|(a, b)| a * b).sum())
+            })
+            .collect()
+    }
+
+    fn power(&mut self, k: usize) -> &Matrix {
+        while self.powers.len() <= k {
+            let last = self.powers.last().unwrap();
+            let squared = (0..last.len())
+                .map(|i| {
+                    (0..last.len())
+                        .map(|j| {
+                            self.arithmetic
+                                .reduce((0..last.len()).map(|l| &last[i][l] * &last[l][j]).sum())
+                        })
+                        .collect()
+                })
+                .collect();
+            self.powers.push(squared);
+        }
+        &self.powers[k]
+    }
+
+    pub fn pair_counts(&mut self, step: u64) -> BTreeMap<PolyPair, BigUint> {
+        let mut counts = self.initial_counts.clone();
+        for k in (0..u64::BITS as usize).filter(|&k| step >> k & 1 == 1) {
+            self.power(k);
+            counts = self.multiply(&self.powers[k], &counts);
+        }
+        self.pairs.iter().copied().zip(counts).collect()
+    }
+
+    /// Every element is the first half of exactly one pair, except for the
+    /// last one of the polymer, which never changes.
+    pub fn element_counts(&mut self, step: u64) -> BTreeMap<u8, BigUint> {
+        let mut result = BTreeMap::<u8, BigUint>::new();
+        *result.entry(self.last_char).or_default() += 1u32;
+        for ((c, _), count) in self.pair_counts(step) {
+            *result.entry(c).or_default() += count;
+        }
+        result
+            .into_iter()
+            .map(|(c, count)| (c, self.arithmetic.reduce(count)))
+            .collect()
+    }
+
+    pub fn element_count(&mut self, step: u64, element: u8) -> BigUint {
+        self.element_counts(step)
+            .remove(&element)
+            .unwrap_or_default()
     }
-    result
 }
 
-fn solve(
-    mut count_map: CountMap,
-    transform_map: &TransformMap,
-    template: &[u8],
+/// The polymer after `n_steps` steps, expanded only where it is read.
+/// `lengths[k]` maps every pair with a rule to the number of characters it
+/// spans after k steps, not counting its second element. Lengths saturate at
+/// `u64::MAX`, which still steers any reachable index correctly.
+pub struct Polymer<'a> {
+    template: &'a [u8],
+    transform_map: &'a TransformMap,
     n_steps: u32,
-) -> MyResult<(u64, u64)> {
-    for _ in 0..n_steps {
-        count_map = do_step(transform_map, count_map);
-    }
-    let char_counts = compute_char_counts(count_map, template[0], template[template.len() - 1]);
-    let &max_count = char_counts.iter().max().ok_or(format!("Empty counts"))?;
-    let &min_count = char_counts
-        .iter()
-        .filter(|x| **x > 0)
-        .min()
-        .ok_or(format!("Empty counts"))?;
-    Ok((max_count, min_count))
+    /// Expanded pair lengths by step count, saturating at `u64::MAX`. Levels
+    /// stop once they no longer change, and the last one stands for all
+    /// later steps.
+    lengths: Vec<HashMap<PolyPair, u64>>,
 }
 
-pub fn run_me(reader: impl BufRead) -> MyResult<()> {
-    let mut lines = reader.lines();
-    let template = lines.next().ok_or(format!("no first line"))??.into_bytes();
-
-    assert_eq!(lines.next().ok_or(format!("no second line"))??.len(), 0);
-
-    let transform_map = lines
-        .map(|line| {
-            let line = line?;
-            let (starting_point, result) = line
-                .split_once(" -> ")
-                .ok_or(format!("Cannot split {}", line))?;
-            let starting_point = starting_point.as_bytes();
-            let result = result.as_bytes();
-            assert_eq!(starting_point.len(), 2);
-            assert_eq!(result.len(), 1);
-            Ok((
-                (starting_point[0], starting_point[1]),
-                [
-                    (starting_point[0], result[0]),
-                    (result[0], starting_point[1]),
-                ],
-            ))
-        })
-        .collect::<MyResult<TransformMap>>()?;
-    let mut initial_count_map = CountMap::new();
-    for (&c1, &c2) in template[..template.len() - 1]
-        .iter()
-        .zip(template[1..].iter())
-    {
-        *initial_count_map.entry((c1, c2)).or_default() += 1;
+enum Segment {
+    Pair(PolyPair, u32),
+    Last(u8),
+}
+
+impl<'a> Polymer<'a> {
+    pub fn new(template: &'a [u8], transform_map: &'a TransformMap, n_steps: u32) -> Polymer<'a> {
+        let mut lengths = vec![transform_map
+            .keys()
+            .map(|&pair| (pair, 1))
+            .collect::<HashMap<_, _>>()];
+        for _ in 0..n_steps {
+            let previous = lengths.last().unwrap();
+            let next = transform_map
+                .iter()
+                .map(|(&pair, [left, right])| {
+                    let left = *previous.get(left).unwrap_or(&1u64);
+                    let right = *previous.get(right).unwrap_or(&1);
+                    (pair, left.saturating_add(right))
+                })
+                .collect::<HashMap<_, _>>();
+            if &next == previous {
+                break;
+            }
+            lengths.push(next);
+        }
+        Polymer {
+            template,
+            transform_map,
+            n_steps,
+            lengths,
+        }
+    }
+
+    fn segment_len(&self, segment: &Segment) -> u64 {
+        match segment {
+            Segment::Pair(pair, steps) => {
+                let level = (*steps as usize).min(self.lengths.len() - 1);
+                *self.lengths[level].get(pair).unwrap_or(&1)
+            }
+            Segment::Last(..) => 1,
+        }
     }
 
-    let (max_count, min_count) = solve(initial_count_map.clone(), &transform_map, &template, 10)?;
-    println!("{} {}", max_count, min_count);
-    println!("Task 1: {}", max_count - min_count);
+    /// Moves `segment` down towards the last stored level without changing
+    /// the elements it starts with, stepping over up to `budget` of them.
+    /// Past that level lengths stay put: a pair shorter than `u64::MAX`
+    /// expands the same at any step count, and a longer one is only left
+    /// through its left half when that half is short. The pairs walked
+    /// through repeat, so whole cycles of the walk are skipped at once.
+    fn descend(&self, segment: Segment, budget: &mut u64) -> Segment {
+        let (mut pair, mut steps) = match segment {
+            Segment::Pair(pair, steps) => (pair, steps),
+            last => return last,
+        };
+        let last_level = self.lengths.len() as u32 - 1;
+        let len = |pair: PolyPair| *self.lengths[last_level as usize].get(&pair).unwrap_or(&1);
+        let mut seen = HashMap::new();
+        while steps > last_level {
+            if len(pair) < u64::MAX {
+                steps = last_level;
+                break;
+            }
+            let [left, right] = self.transform_map[&pair];
+            let left_len = len(left);
+            if left_len == u64::MAX {
+                pair = left;
+            } else if left_len <= *budget {
+                *budget -= left_len;
+                pair = right;
+            } else {
+                break;
+            }
+            steps -= 1;
+            if let Some((cycle_steps, cycle_budget)) = seen.insert(pair, (steps, *budget)) {
+                let period = cycle_steps - steps;
+                let per_cycle = cycle_budget - *budget;
+                let mut cycles = ((steps - last_level) / period) as u64;
+                if let Some(affordable) = budget.checked_div(per_cycle) {
+                    cycles = cycles.min(affordable);
+                }
+                steps -= cycles as u32 * period;
+                *budget -= cycles * per_cycle;
+                seen.clear();
+            }
+        }
+        Segment::Pair(pair, steps)
+    }
 
-    let (max_count, min_count) = solve(initial_count_map.clone(), &transform_map, &template, 40)?;
-    println!("{} {}", max_count, min_count);
-    println!("Task 2: {}", max_count - min_count);
+    pub fn len(&self) -> u64 {
+        self.template
+            .iter()
+            .zip(self.template.iter().skip(1))
+            .map(|(&a, &b)| self.segment_len(&Segment::Pair((a, b), self.n_steps)))
+            .fold(1, u64::saturating_add)
+    }
+
+    /// The elements from index `start` onwards, expanded lazily.
+    pub fn chars_from(&self, mut start: u64) -> PolymerChars<'_> {
+        let mut stack = vec![];
+        if let Some(&last) = self.template.last() {
+            stack.push(Segment::Last(last));
+        }
+        for (&a, &b) in self.template.iter().zip(self.template.iter().skip(1)).rev() {
+            stack.push(Segment::Pair((a, b), self.n_steps));
+        }
+        while start > 0 {
+            let segment = match stack.pop() {
+                Some(segment) => self.descend(segment, &mut start),
+                None => break,
+            };
+            let segment_len = self.segment_len(&segment);
+            if segment_len <= start {
+                start -= segment_len;
+                continue;
+            }
+            if let Segment::Pair(pair, steps) = segment {
+                // Longer than one character, so it has a rule to split by.
+                let [left, right] = self.transform_map[&pair];
+                stack.push(Segment::Pair(right, steps - 1));
+                stack.push(Segment::Pair(left, steps - 1));
+            }
+        }
+        PolymerChars {
+            polymer: self,
+            stack,
+        }
+    }
+
+    pub fn window(&self, start: u64, len: usize) -> String {
+        self.chars_from(start).take(len).map(char::from).collect()
+    }
+}
+
+pub struct PolymerChars<'a> {
+    polymer: &'a Polymer<'a>,
+    stack: Vec<Segment>,
+}
+
+impl<'a> Iterator for PolymerChars<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            match self.polymer.descend(self.stack.pop()?, &mut 0) {
+                Segment::Last(c) => return Some(c),
+                Segment::Pair(pair, steps) => match self.polymer.transform_map.get(&pair) {
+                    Some([left, right]) if steps > 0 => {
+                        self.stack.push(Segment::Pair(*right, steps - 1));
+                        self.stack.push(Segment::Pair(*left, steps - 1));
+                    }
+                    _ => return Some(pair.0),
+                },
+            }
+        }
+    }
+}
+
+pub fn run_me(reader: impl BufRead) -> MyResult<()> {
+    let (template, transform_map) = parse_input(reader)?;
+
+    let mut growth = PolymerGrowth::new(&template, &transform_map, Arithmetic::Exact)?;
+    for (task_no, n_steps) in [(1, 10), (2, 40)] {
+        let element_counts = growth.element_counts(n_steps);
+        let max_count = element_counts.values().max().ok_or("Empty counts")?;
+        let min_count = element_counts
+            .values()
+            .filter(|x| **x > BigUint::default())
+            .min()
+            .ok_or("Empty counts")?;
+        println!("{} {}", max_count, min_count);
+        println!("Task {}: {}", task_no, max_count - min_count);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+
+    const EXAMPLE_RULES: &[&str] = &[
+        "CH -> B", "HH -> N", "CB -> H", "NH -> C", "HB -> C", "HC -> B", "HN -> C", "NN -> C",
+        "BH -> H", "NC -> B", "NB -> B", "BN -> B", "BB -> N", "BC -> B", "CC -> N", "CN -> C",
+    ];
+
+    fn example_rules() -> TransformMap {
+        EXAMPLE_RULES
+            .iter()
+            .map(|rule| {
+                let b = rule.as_bytes();
+                ((b[0], b[1]), [(b[0], b[6]), (b[6], b[1])])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_element_counts() -> MyResult<()> {
+        let mut growth = PolymerGrowth::new(b"NNCB", &example_rules(), Arithmetic::Exact)?;
+        assert_eq!(growth.element_count(10, b'B'), BigUint::from(1749u32));
+        assert_eq!(growth.element_count(10, b'H'), BigUint::from(161u32));
+        assert_eq!(
+            growth.element_count(40, b'B'),
+            BigUint::from(2192039569602u64)
+        );
+        assert_eq!(growth.element_count(40, b'H'), BigUint::from(3849876073u64));
+        assert_eq!(growth.element_count(0, b'N'), BigUint::from(2u32));
+        assert_eq!(growth.element_count(3, b'X'), BigUint::default());
+        Ok(())
+    }
+
+    /// The plain pair-count update, as a reference for `PolymerGrowth`.
+    fn do_step(transform_map: &TransformMap, count_map: CountMap) -> CountMap {
+        let mut result = CountMap::new();
+
+        for (pair, count) in count_map {
+            if let Some([new_pair_1, new_pair_2]) = transform_map.get(&pair) {
+                *result.entry(*new_pair_1).or_default() += count;
+                *result.entry(*new_pair_2).or_default() += count;
+            } else {
+                *result.entry(pair).or_default() += count;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_matches_step_by_step() -> MyResult<()> {
+        let template = b"NNCB";
+        let transform_map = example_rules();
+        let mut count_map = CountMap::new();
+        for (&c1, &c2) in template.iter().zip(template.iter().skip(1)) {
+            *count_map.entry((c1, c2)).or_default() += 1;
+        }
+        let mut growth = PolymerGrowth::new(template, &transform_map, Arithmetic::Exact)?;
+        for n_steps in 0..20 {
+            let pair_counts = growth.pair_counts(n_steps);
+            for (pair, count) in &count_map {
+                assert_eq!(pair_counts[pair], BigUint::from(*count));
+            }
+            count_map = do_step(&transform_map, count_map);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |input: &str| {
+            *parse_input(input.as_bytes())
+                .unwrap_err()
+                .downcast::<RuleError>()
+                .unwrap()
+        };
+        assert_eq!(
+            parse("NN\n\nNN -> C\nNC -> B\nNN -> C"),
+            RuleError::Duplicate {
+                line_no: 5,
+                first_line_no: 3
+            }
+        );
+        assert_eq!(
+            parse("NN\n\nNN -> C\nNN -> B"),
+            RuleError::Conflicting {
+                line_no: 4,
+                first_line_no: 3
+            }
+        );
+        assert_eq!(
+            parse("NN\n\nNÑ -> C"),
+            RuleError::NonAscii {
+                line_no: 3,
+                element: 'Ñ'
+            }
+        );
+        assert_eq!(
+            parse("NN\n\nNNN -> C"),
+            RuleError::Malformed {
+                line_no: 3,
+                line: String::from("NNN -> C")
+            }
+        );
+        assert_eq!(parse("NN\nCH -> B"), RuleError::MissingSeparator);
+    }
+
+    #[test]
+    fn test_polymer_window() -> MyResult<()> {
+        let (template, transform_map) =
+            parse_input(format!("NNCB\n\n{}", EXAMPLE_RULES.join("\n")).as_bytes())?;
+        assert_eq!(
+            Polymer::new(&template, &transform_map, 0).window(0, 100),
+            "NNCB"
+        );
+        let step_4 = "NBBNBNBBCCNBCNCCNBBNBBNBBBNBBNBBCBHCBHHNHCBBCBHCB";
+        let polymer = Polymer::new(&template, &transform_map, 4);
+        assert_eq!(polymer.len(), step_4.len() as u64);
+        for start in 0..step_4.len() {
+            assert_eq!(
+                polymer.window(start as u64, 7),
+                &step_4[start..(start + 7).min(step_4.len())]
+            );
+        }
+
+        let polymer = Polymer::new(&template, &transform_map, 100);
+        assert_eq!(polymer.len(), u64::MAX);
+        assert_eq!(polymer.window(0, 12), "NBBNBBNBBNBB");
+
+        let saturated = Polymer::new(&template, &transform_map, 1_000_000);
+        assert!(saturated.lengths.len() < 100);
+        assert_eq!(saturated.len(), u64::MAX);
+        assert_eq!(saturated.window(0, 12), "NBBNBBNBBNBB");
+        assert_eq!(saturated.window(1 << 50, 30), polymer.window(1 << 50, 30));
+        Ok(())
+    }
+
+    /// Checks queries against the same polymer with every level stored,
+    /// whose queries walk down them one by one.
+    fn check_saturated(
+        template: &[u8],
+        transform_map: &TransformMap,
+        n_steps: u32,
+        starts: &[u64],
+    ) {
+        let polymer = Polymer::new(template, transform_map, n_steps);
+        let mut reference = Polymer::new(template, transform_map, n_steps);
+        while reference.lengths.len() <= n_steps as usize {
+            reference
+                .lengths
+                .push(reference.lengths.last().unwrap().clone());
+        }
+        assert_eq!(polymer.len(), reference.len());
+        for &start in starts {
+            assert_eq!(
+                polymer.window(start, 20),
+                reference.window(start, 20),
+                "start {}",
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn test_saturated_descent() -> MyResult<()> {
+        // The walk down from AB goes round AB, AC, DC and DB, splitting off
+        // an A and a D on the way.
+        let (template, transform_map) = parse_input(
+            &b"AB\n\nAB -> C\nAC -> D\nDC -> B\nDB -> A\nCB -> C\nBC -> C\nCC -> C"[..],
+        )?;
+        let polymer = Polymer::new(&template, &transform_map, 1_000_000);
+        assert_eq!(polymer.window(0, 6), "ADADAD");
+        assert_eq!(polymer.window(400_000, 4), "ADAD");
+        check_saturated(
+            &template,
+            &transform_map,
+            300,
+            &[0, 5, 100, 200, 230, 260, 1 << 40],
+        );
+
+        let mut rng = TestRng(14);
+        for _ in 0..200 {
+            let elements = b"ABCD";
+            let mut pick = || elements[rng.below(4) as usize];
+            let template = (0..4).map(|_| pick()).collect::<Vec<_>>();
+            let mut transform_map = TransformMap::new();
+            for &a in elements {
+                for &b in elements {
+                    if rng.below(4) > 0 {
+                        let c = elements[rng.below(4) as usize];
+                        transform_map.insert((a, b), [(a, c), (c, b)]);
+                    }
+                }
+            }
+            let n_steps = 100 + rng.below(200) as u32;
+            let starts = (0..5)
+                .map(|_| rng.next_u64() >> rng.below(64))
+                .collect::<Vec<_>>();
+            check_saturated(&template, &transform_map, n_steps, &starts);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_modulo() -> MyResult<()> {
+        let mut exact = PolymerGrowth::new(b"NNCB", &example_rules(), Arithmetic::Exact)?;
+        let mut modulo =
+            PolymerGrowth::new(b"NNCB", &example_rules(), Arithmetic::Modulo(1_000_007))?;
+        let exact_count = exact.element_count(200, b'N');
+        assert!(exact_count.bits() > 64);
+        assert_eq!(modulo.element_count(200, b'N'), exact_count % 1_000_007u32);
+        Ok(())
+    }
+}
@@ -1,29 +1,65 @@
+use crate::common::GeneralError;
 use crate::MyResult;
+use ndarray::stack;
 use ndarray::Array1;
 use ndarray::Array2;
 use ndarray::Axis;
-use ndarray::{concatenate, stack};
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
+use std::env::args;
 use std::io::BufRead;
 
-fn explore(risk_level: &Array2<i32>) -> i32 {
+pub const ORTHOGONAL: &[(i32, i32)] = &[(-1, 0), (1, 0), (0, -1), (0, 1)];
+pub const WITH_DIAGONALS: &[(i32, i32)] = &[
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, -1),
+    (0, 1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LowestRiskPath {
+    pub risk: i32,
+    pub path: Vec<(usize, usize)>,
+}
+
+/// Dijkstra from `start` to `end`; the risk of `start` itself is not counted.
+fn explore(
+    risk_level: &Array2<i32>,
+    start: (usize, usize),
+    end: (usize, usize),
+    moves: &[(i32, i32)],
+) -> MyResult<LowestRiskPath> {
     let mi = risk_level.shape()[0] as i32;
     let mj = risk_level.shape()[1] as i32;
+    for (i, j) in [start, end] {
+        if risk_level.get([i, j]).is_none() {
+            return Err(Box::new(GeneralError(format!(
+                "({}, {}) is outside the {}x{} map",
+                i, j, mi, mj
+            ))));
+        }
+    }
     let mut min_risk = Array2::from_elem((risk_level.shape()[0], risk_level.shape()[1]), i32::MAX);
+    let mut came_from = Array2::from_elem(min_risk.raw_dim(), None);
     let mut heap = BinaryHeap::new();
 
-    min_risk[[0, 0]] = 0;
-    heap.push(Reverse((0, (0i32, 0i32))));
+    min_risk[start] = 0;
+    heap.push(Reverse((0, (start.0 as i32, start.1 as i32))));
 
     while let Some(Reverse((current_risk, (i, j)))) = heap.pop() {
         if current_risk > min_risk[[i as usize, j as usize]] {
             continue;
         }
-        if i + 1 == mi && j + 1 == mj {
+        if (i as usize, j as usize) == end {
             break;
         }
-        for (ni, nj) in [(i - 1, j), (i + 1, j), (i, j - 1), (i, j + 1)] {
+        for (di, dj) in moves {
+            let (ni, nj) = (i + di, j + dj);
             if !(0..mi).contains(&ni) || !(0..mj).contains(&nj) {
                 continue;
             }
@@ -31,11 +67,52 @@ fn explore(risk_level: &Array2<i32>) -> i32 {
             let mr = &mut min_risk[[ni as usize, nj as usize]];
             if candidate_risk < *mr {
                 *mr = candidate_risk;
+                came_from[[ni as usize, nj as usize]] = Some((i as usize, j as usize));
                 heap.push(Reverse((candidate_risk, (ni, nj))));
             }
         }
     }
-    min_risk[[mi as usize - 1, mj as usize - 1]]
+    if min_risk[end] == i32::MAX {
+        return Err(Box::new(GeneralError(format!(
+            "{:?} cannot be reached from {:?}",
+            end, start
+        ))));
+    }
+    let mut path = vec![end];
+    while let Some(previous) = came_from[*path.last().unwrap()] {
+        path.push(previous);
+    }
+    path.reverse();
+    Ok(LowestRiskPath {
+        risk: min_risk[end],
+        path,
+    })
+}
+
+/// Repeats the map `tile_factor` times in both directions, raising the risk by
+/// one per tile step and wrapping values above `wrap_modulus` back to 1.
+fn tile(risk_level: &Array2<i32>, tile_factor: usize, wrap_modulus: i32) -> Array2<i32> {
+    let (h, w) = (risk_level.shape()[0], risk_level.shape()[1]);
+    Array2::from_shape_fn((h * tile_factor, w * tile_factor), |(i, j)| {
+        let increase = (i / h + j / w) as i32;
+        1 + (risk_level[[i % h, j % w]] - 1 + increase) % wrap_modulus
+    })
+}
+
+/// Shows the risk of the cells on `path` and blanks out the rest.
+fn render_overlay(risk_level: &Array2<i32>, path: &[(usize, usize)]) -> Vec<String> {
+    let mut overlay = Array2::from_elem(risk_level.raw_dim(), '.');
+    for &cell in path {
+        overlay[cell] = char::from_digit(risk_level[cell] as u32, 36).unwrap_or('*');
+    }
+    overlay
+        .axis_iter(Axis(0))
+        .map(|row| row.iter().collect())
+        .collect()
+}
+
+fn bottom_right(risk_level: &Array2<i32>) -> (usize, usize) {
+    (risk_level.shape()[0] - 1, risk_level.shape()[1] - 1)
 }
 
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
@@ -54,31 +131,80 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     )?;
     // println!("{:?}", risk_level);
 
-    println!("Task 1: {}", explore(&risk_level));
+    let task_1 = explore(&risk_level, (0, 0), bottom_right(&risk_level), ORTHOGONAL)?;
+    println!("Task 1: {}", task_1.risk);
 
-    let risk_level = concatenate![
-        Axis(0),
-        risk_level.clone(),
-        risk_level.clone() + 1,
-        risk_level.clone() + 2,
-        risk_level.clone() + 3,
-        risk_level.clone() + 4
-    ];
-    let mut risk_level = concatenate![
-        Axis(1),
-        risk_level.clone(),
-        risk_level.clone() + 1,
-        risk_level.clone() + 2,
-        risk_level.clone() + 3,
-        risk_level.clone() + 4
-    ];
-    risk_level.iter_mut().for_each(|x| {
-        *x = 1 + (*x - 1) % 9;
-    });
+    let risk_level = tile(&risk_level, 5, 9);
+    let task_2 = explore(&risk_level, (0, 0), bottom_right(&risk_level), ORTHOGONAL)?;
+    println!("Task 2: {}", task_2.risk);
+    if args().nth(2).as_deref() == Some("--overlay") {
+        for line in render_overlay(&risk_level, &task_2.path) {
+            println!("{}", line);
+        }
+    }
 
-    // println!("{:?}", risk_level);
+    Ok(())
+}
 
-    println!("Task 2: {}", explore(&risk_level));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
 
-    Ok(())
+    fn example() -> Array2<i32> {
+        arr2(&[
+            [1, 1, 6, 3, 7, 5, 1, 7, 4, 2],
+            [1, 3, 8, 1, 3, 7, 3, 6, 7, 2],
+            [2, 1, 3, 6, 5, 1, 1, 3, 2, 8],
+            [3, 6, 9, 4, 9, 3, 1, 5, 6, 9],
+            [7, 4, 6, 3, 4, 1, 7, 1, 1, 1],
+            [1, 3, 1, 9, 1, 2, 8, 1, 3, 7],
+            [1, 3, 5, 9, 9, 1, 2, 4, 2, 1],
+            [3, 1, 2, 5, 4, 2, 1, 6, 3, 9],
+            [1, 2, 9, 3, 1, 3, 8, 5, 2, 1],
+            [2, 3, 1, 1, 9, 4, 4, 5, 8, 1],
+        ])
+    }
+
+    #[test]
+    fn test_example() -> MyResult<()> {
+        let risk_level = example();
+        let result = explore(&risk_level, (0, 0), (9, 9), ORTHOGONAL)?;
+        assert_eq!(result.risk, 40);
+        assert_eq!(result.path.len(), 19);
+        assert_eq!(
+            result
+                .path
+                .iter()
+                .skip(1)
+                .map(|&x| risk_level[x])
+                .sum::<i32>(),
+            40
+        );
+        assert_eq!(render_overlay(&risk_level, &result.path)[0], "1.........");
+        assert_eq!(render_overlay(&risk_level, &result.path)[9], ".........1");
+
+        let tiled = tile(&risk_level, 5, 9);
+        assert_eq!(tiled[[0, 49]], 6);
+        assert_eq!(tiled[[49, 49]], 9);
+        assert_eq!(explore(&tiled, (0, 0), (49, 49), ORTHOGONAL)?.risk, 315);
+        Ok(())
+    }
+
+    #[test]
+    fn test_options() -> MyResult<()> {
+        let risk_level = arr2(&[[1, 9, 9], [9, 1, 9], [9, 9, 1]]);
+        let straight = explore(&risk_level, (0, 0), (2, 2), ORTHOGONAL)?;
+        assert_eq!(straight.risk, 20);
+        let diagonal = explore(&risk_level, (0, 0), (2, 2), WITH_DIAGONALS)?;
+        assert_eq!(diagonal.risk, 2);
+        assert_eq!(diagonal.path, [(0, 0), (1, 1), (2, 2)]);
+        let backwards = explore(&risk_level, (2, 2), (1, 1), WITH_DIAGONALS)?;
+        assert_eq!(backwards.path, [(2, 2), (1, 1)]);
+        assert!(explore(&risk_level, (0, 0), (3, 0), ORTHOGONAL).is_err());
+        assert!(explore(&risk_level, (0, 0), (2, 2), &[(0, 1)]).is_err());
+        assert_eq!(explore(&risk_level, (0, 0), (0, 2), &[(0, 1)])?.risk, 18);
+        assert_eq!(tile(&risk_level, 2, 4)[[3, 5]], 3);
+        Ok(())
+    }
 }
@@ -1,68 +1,592 @@
 use crate::MyResult;
-use std::{collections::VecDeque, fmt::Result, io::BufRead, iter::from_fn};
+use num_bigint::BigUint;
+use std::convert::TryFrom;
+use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::BufRead;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 enum Content {
-    Literal(i64),
+    Literal(BigUint),
     Subpackets(Vec<Packet>),
 }
 
-#[derive(Debug)]
+/// `offset` is the bit offset the packet was decoded from, if any. It is
+/// decoder metadata and does not take part in equality.
+#[derive(Debug, Clone)]
 struct Packet {
     version: i32,
     type_id: i32,
     content: Content,
+    offset: Option<usize>,
 }
 
-type BinaryStream = VecDeque<char>;
+impl PartialEq for Packet {
+    fn eq(&self, other: &Packet) -> bool {
+        self.version == other.version
+            && self.type_id == other.type_id
+            && self.content == other.content
+    }
+}
+
+impl Eq for Packet {}
+
+fn operator_name(type_id: i32) -> String {
+    match type_id {
+        0 => String::from("sum"),
+        1 => String::from("product"),
+        2 => String::from("min"),
+        3 => String::from("max"),
+        4 => String::from("lit"),
+        5 => String::from("gt"),
+        6 => String::from("lt"),
+        7 => String::from("eq"),
+        type_id => format!("type{}", type_id),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Annotations {
+    versions: bool,
+    offsets: bool,
+}
+
+/// S-expression rendering of a packet tree, e.g.
+/// `(sum (lit 3) (gt (lit 5) (lit 2)))`. Annotations go right after the
+/// operator name, as in `(lit v6 @22 3)`.
+struct PacketDump<'a> {
+    packet: &'a Packet,
+    annotations: Annotations,
+}
+
+impl Packet {
+    fn dump(&self, annotations: Annotations) -> PacketDump<'_> {
+        PacketDump {
+            packet: self,
+            annotations,
+        }
+    }
+}
+
+impl Display for PacketDump<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let packet = self.packet;
+        write!(f, "({}", operator_name(packet.type_id))?;
+        if self.annotations.versions {
+            write!(f, " v{}", packet.version)?;
+        }
+        if let (true, Some(offset)) = (self.annotations.offsets, packet.offset) {
+            write!(f, " @{}", offset)?;
+        }
+        match &packet.content {
+            Content::Literal(value) => write!(f, " {}", value)?,
+            Content::Subpackets(subpackets) => {
+                for subpacket in subpackets {
+                    write!(f, " {}", subpacket.dump(self.annotations))?;
+                }
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+impl Display for Packet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.dump(Annotations::default()))
+    }
+}
+
+/// Bit offsets count from the start of the transmission.
+#[derive(Debug, PartialEq, Eq)]
+enum DecodeError {
+    UnexpectedEnd { offset: usize, wanted: usize },
+    InvalidHexDigit { offset: usize, digit: char },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Reads bits straight out of the hex digits of a transmission. A reader for
+/// a length-type-0 body only sees the bits of that body, but keeps reporting
+/// offsets relative to the whole transmission.
+struct BitReader<'a> {
+    hex_digits: &'a [u8],
+    position: usize,
+    end: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(hex_input: &'a str) -> BitReader<'a> {
+        BitReader {
+            hex_digits: hex_input.as_bytes(),
+            position: 0,
+            end: hex_input.len() * 4,
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.position == self.end
+    }
+
+    fn ensure_available(&self, wanted: usize) -> Result<(), DecodeError> {
+        if self.end - self.position < wanted {
+            return Err(DecodeError::UnexpectedEnd {
+                offset: self.position,
+                wanted,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, DecodeError> {
+        self.ensure_available(1)?;
+        let digit = self.hex_digits[self.position / 4] as char;
+        let value = digit.to_digit(16).ok_or(DecodeError::InvalidHexDigit {
+            offset: self.position - self.position % 4,
+            digit,
+        })?;
+        let bit = value >> (3 - self.position % 4) & 1 == 1;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_number(&mut self, n_bits: usize) -> Result<u64, DecodeError> {
+        self.ensure_available(n_bits)?;
+        let mut value = 0;
+        for _ in 0..n_bits {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
 
-fn read_number(binary_stream: &mut BinaryStream, n_bits: usize) -> MyResult<i64> {
-    if binary_stream.len() < n_bits {
-        return Err(format!(
-            "expected {} bits but got only {}",
-            n_bits,
-            binary_stream.len()
-        ))?;
+    /// Splits off the next `n_bits` bits as a reader of their own.
+    fn take(&mut self, n_bits: usize) -> Result<BitReader<'a>, DecodeError> {
+        self.ensure_available(n_bits)?;
+        let inner = BitReader {
+            hex_digits: self.hex_digits,
+            position: self.position,
+            end: self.position + n_bits,
+        };
+        self.position += n_bits;
+        Ok(inner)
     }
-    Ok(i64::from_str_radix(
-        &binary_stream.drain(..n_bits).collect::<String>(),
-        2,
-    )?)
 }
 
-fn read_literal(binary_stream: &mut BinaryStream) -> MyResult<Content> {
-    let mut value = 0;
-    let mut has_more = 1;
-    while has_more != 0 {
-        has_more = read_number(binary_stream, 1)?;
-        value = value * 16 + read_number(binary_stream, 4)?;
+fn read_literal(reader: &mut BitReader) -> MyResult<Content> {
+    let mut value = BigUint::default();
+    let mut has_more = true;
+    while has_more {
+        has_more = reader.read_bit()?;
+        value = value * 16u32 + reader.read_number(4)?;
     }
     Ok(Content::Literal(value))
 }
 
-fn read_packet(binary_stream: &mut BinaryStream) -> MyResult<Packet> {
-    let version = read_number(binary_stream, 3)? as i32;
-    let type_id = read_number(binary_stream, 3)? as i32;
+fn read_packet(reader: &mut BitReader) -> MyResult<Packet> {
+    let offset = reader.position;
+    let version = reader.read_number(3)? as i32;
+    let type_id = reader.read_number(3)? as i32;
     let content = if type_id == 4 {
-        read_literal(binary_stream)?
+        read_literal(reader)?
     } else {
-        let length_id = read_number(binary_stream, 1)?;
-        Content::Subpackets(if length_id == 0 {
-            let inner_len = read_number(binary_stream, 15)? as usize;
-            let mut inner_stream = binary_stream.drain(..inner_len).collect::<VecDeque<_>>();
-            from_fn(|| read_packet(&mut inner_stream).ok()).collect()
+        let length_id = reader.read_bit()?;
+        Content::Subpackets(if !length_id {
+            let inner_len = reader.read_number(15)? as usize;
+            let mut inner_reader = reader.take(inner_len)?;
+            let mut subpackets = Vec::new();
+            while !inner_reader.is_at_end() {
+                subpackets.push(read_packet(&mut inner_reader)?);
+            }
+            subpackets
         } else {
-            let num_packets = read_number(binary_stream, 11)?;
+            let num_packets = reader.read_number(11)?;
             (0..num_packets)
-                .map(|_| read_packet(binary_stream))
+                .map(|_| read_packet(reader))
                 .collect::<MyResult<Vec<_>>>()?
         })
     };
-    return Ok(Packet {
+    Ok(Packet {
         version,
         type_id,
         content,
-    });
+        offset: Some(offset),
+    })
+}
+
+/// How operator packets announce their subpackets: by their total length in
+/// bits (length type ID 0) or by their count (length type ID 1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LengthType {
+    BitLength,
+    PacketCount,
+}
+
+fn write_number(bits: &mut Vec<bool>, value: i64, n_bits: usize) -> MyResult<()> {
+    if value < 0 || (n_bits < 63 && value >> n_bits != 0) {
+        return Err(format!("{} does not fit in {} bits", value, n_bits).into());
+    }
+    bits.extend((0..n_bits).rev().map(|i| value >> i & 1 == 1));
+    Ok(())
+}
+
+/// Writes the value in as few 4-bit groups as possible.
+fn write_literal(bits: &mut Vec<bool>, value: &BigUint) -> MyResult<()> {
+    let groups = value.to_radix_be(16);
+    for (i, &group) in groups.iter().enumerate() {
+        write_number(bits, (i + 1 < groups.len()) as i64, 1)?;
+        write_number(bits, group as i64, 4)?;
+    }
+    Ok(())
+}
+
+fn write_packet(bits: &mut Vec<bool>, packet: &Packet, length_type: LengthType) -> MyResult<()> {
+    write_number(bits, packet.version as i64, 3)?;
+    write_number(bits, packet.type_id as i64, 3)?;
+    match (&packet.content, packet.type_id) {
+        (Content::Literal(value), 4) => write_literal(bits, value),
+        (Content::Subpackets(subpackets), type_id) if type_id != 4 => match length_type {
+            LengthType::BitLength => {
+                let mut inner_bits = Vec::new();
+                for subpacket in subpackets {
+                    write_packet(&mut inner_bits, subpacket, length_type)?;
+                }
+                write_number(bits, 0, 1)?;
+                write_number(bits, inner_bits.len() as i64, 15)?;
+                bits.extend(inner_bits);
+                Ok(())
+            }
+            LengthType::PacketCount => {
+                write_number(bits, 1, 1)?;
+                write_number(bits, subpackets.len() as i64, 11)?;
+                subpackets
+                    .iter()
+                    .try_for_each(|subpacket| write_packet(bits, subpacket, length_type))
+            }
+        },
+        (content, type_id) => Err(format!("type ID {} cannot hold {:?}", type_id, content))?,
+    }
+}
+
+/// Serialises the packet as a hex transmission, zero-padded to whole bytes.
+fn encode_packet(packet: &Packet, length_type: LengthType) -> MyResult<String> {
+    let mut bits = Vec::new();
+    write_packet(&mut bits, packet, length_type)?;
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+    Ok(bits
+        .chunks(4)
+        .map(|digit| {
+            let value = digit.iter().fold(0, |acc, &bit| acc * 2 + bit as u32);
+            char::from_digit(value, 16).unwrap().to_ascii_uppercase()
+        })
+        .collect())
+}
+
+/// Compile errors carry the 1-based column of the offending token.
+#[derive(Debug, PartialEq, Eq)]
+enum CompileErrorKind {
+    UnexpectedChar(char),
+    UnexpectedToken {
+        expected: String,
+        found: String,
+    },
+    UnknownFunction(String),
+    WrongOperandCount {
+        operator: &'static str,
+        expected: &'static str,
+        found: usize,
+    },
+    ChainedComparison,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct CompileError {
+    column: usize,
+    kind: CompileErrorKind,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "column {}: ", self.column)?;
+        match &self.kind {
+            CompileErrorKind::UnexpectedChar(c) => write!(f, "unexpected character '{}'", c),
+            CompileErrorKind::UnexpectedToken { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            CompileErrorKind::UnknownFunction(name) => write!(f, "unknown function {}", name),
+            CompileErrorKind::WrongOperandCount {
+                operator,
+                expected,
+                found,
+            } => write!(f, "{} takes {}, got {}", operator, expected, found),
+            CompileErrorKind::ChainedComparison => {
+                write!(f, "comparisons cannot be chained, use parentheses")
+            }
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    Number(BigUint),
+    Name(String),
+    Symbol(&'static str),
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &["==", "+", "*", ">", "<", "(", ")", ","];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, CompileError> {
+    let mut tokens = Vec::new();
+    let mut rest = source.char_indices().peekable();
+    while let Some(&(i, c)) = rest.peek() {
+        let column = i + 1;
+        if c.is_whitespace() {
+            rest.next();
+        } else if c.is_ascii_digit() || c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&(_, c)) = rest
+                .peek()
+                .filter(|(_, c)| c.is_alphanumeric() || *c == '_')
+            {
+                word.push(c);
+                rest.next();
+            }
+            tokens.push((
+                column,
+                match BigUint::parse_bytes(word.as_bytes(), 10) {
+                    Some(value) => Token::Number(value),
+                    None if c.is_ascii_digit() => {
+                        return Err(CompileError {
+                            column,
+                            kind: CompileErrorKind::UnexpectedToken {
+                                expected: String::from("a number"),
+                                found: word,
+                            },
+                        })
+                    }
+                    None => Token::Name(word),
+                },
+            ));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| source[i..].starts_with(*symbol))
+                .ok_or(CompileError {
+                    column,
+                    kind: CompileErrorKind::UnexpectedChar(c),
+                })?;
+            for _ in 0..symbol.len() {
+                rest.next();
+            }
+            tokens.push((column, Token::Symbol(symbol)));
+        }
+    }
+    tokens.push((source.len() + 1, Token::End));
+    Ok(tokens)
+}
+
+/// Recursive-descent compiler from infix expressions to operator packets:
+///
+/// ```text
+/// comparison := sum (('>' | '<' | '==') sum)?
+/// sum        := product ('+' product)*
+/// product    := atom ('*' atom)*
+/// atom       := number | name '(' comparison (',' comparison)* ')' | '(' comparison ')'
+/// ```
+///
+/// Chains of `+` or `*` become a single packet with all operands. Functions
+/// are the operator names of the S-expression dump.
+struct Compiler {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+fn operator_packet(type_id: i32, subpackets: Vec<Packet>) -> Packet {
+    Packet {
+        version: 0,
+        type_id,
+        content: Content::Subpackets(subpackets),
+        offset: None,
+    }
+}
+
+impl Compiler {
+    fn peek(&self) -> &(usize, Token) {
+        &self.tokens[self.position]
+    }
+
+    fn advance(&mut self) -> (usize, Token) {
+        let token = self.tokens[self.position].clone();
+        if token.1 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        let is_match = matches!(self.peek(), (_, Token::Symbol(s)) if *s == symbol);
+        if is_match {
+            self.advance();
+        }
+        is_match
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), CompileError> {
+        if self.accept(symbol) {
+            return Ok(());
+        }
+        let (column, found) = self.peek();
+        Err(CompileError {
+            column: *column,
+            kind: CompileErrorKind::UnexpectedToken {
+                expected: format!("'{}'", symbol),
+                found: found.to_string(),
+            },
+        })
+    }
+
+    fn comparison(&mut self) -> Result<Packet, CompileError> {
+        let left = self.sum()?;
+        for (symbol, type_id) in [(">", 5), ("<", 6), ("==", 7)] {
+            if self.accept(symbol) {
+                let right = self.sum()?;
+                let (column, next) = self.peek();
+                if matches!(next, Token::Symbol(">" | "<" | "==")) {
+                    return Err(CompileError {
+                        column: *column,
+                        kind: CompileErrorKind::ChainedComparison,
+                    });
+                }
+                return Ok(operator_packet(type_id, vec![left, right]));
+            }
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Packet, CompileError> {
+        let mut operands = vec![self.product()?];
+        while self.accept("+") {
+            operands.push(self.product()?);
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().unwrap(),
+            _ => operator_packet(0, operands),
+        })
+    }
+
+    fn product(&mut self) -> Result<Packet, CompileError> {
+        let mut operands = vec![self.atom()?];
+        while self.accept("*") {
+            operands.push(self.atom()?);
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().unwrap(),
+            _ => operator_packet(1, operands),
+        })
+    }
+
+    fn atom(&mut self) -> Result<Packet, CompileError> {
+        match self.advance() {
+            (_, Token::Number(value)) => Ok(Packet {
+                version: 0,
+                type_id: 4,
+                content: Content::Literal(value),
+                offset: None,
+            }),
+            (_, Token::Symbol("(")) => {
+                let inner = self.comparison()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            (column, Token::Name(name)) => {
+                let (operator, type_id) = match name.as_str() {
+                    "sum" => ("sum", 0),
+                    "product" => ("product", 1),
+                    "min" => ("min", 2),
+                    "max" => ("max", 3),
+                    "gt" => ("gt", 5),
+                    "lt" => ("lt", 6),
+                    "eq" => ("eq", 7),
+                    _ => {
+                        return Err(CompileError {
+                            column,
+                            kind: CompileErrorKind::UnknownFunction(name),
+                        })
+                    }
+                };
+                self.expect("(")?;
+                let mut operands = Vec::new();
+                if !self.accept(")") {
+                    operands.push(self.comparison()?);
+                    while self.accept(",") {
+                        operands.push(self.comparison()?);
+                    }
+                    self.expect(")")?;
+                }
+                let (expected, count_ok) = if type_id >= 5 {
+                    ("exactly 2 operands", operands.len() == 2)
+                } else {
+                    ("at least 1 operand", !operands.is_empty())
+                };
+                if !count_ok {
+                    return Err(CompileError {
+                        column,
+                        kind: CompileErrorKind::WrongOperandCount {
+                            operator,
+                            expected,
+                            found: operands.len(),
+                        },
+                    });
+                }
+                Ok(operator_packet(type_id, operands))
+            }
+            (column, found) => Err(CompileError {
+                column,
+                kind: CompileErrorKind::UnexpectedToken {
+                    expected: String::from("a number, a function or '('"),
+                    found: found.to_string(),
+                },
+            }),
+        }
+    }
+}
+
+fn compile(source: &str) -> Result<Packet, CompileError> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let packet = compiler.comparison()?;
+    let (column, rest) = compiler.peek();
+    if *rest != Token::End {
+        return Err(CompileError {
+            column: *column,
+            kind: CompileErrorKind::UnexpectedToken {
+                expected: String::from("end of input"),
+                found: rest.to_string(),
+            },
+        });
+    }
+    Ok(packet)
 }
 
 fn sum_version(packet: &Packet) -> i32 {
@@ -73,38 +597,429 @@ fn sum_version(packet: &Packet) -> i32 {
         }
 }
 
-fn eval(packet: &Packet) -> i64 {
-    match packet.content {
-        Content::Literal(x) => x,
-        Content::Subpackets(ref items) => match packet.type_id {
-            0 => items.iter().map(eval).sum(),
-            1 => items.iter().map(eval).product(),
-            2 => items.iter().map(eval).min().unwrap(),
-            3 => items.iter().map(eval).max().unwrap(),
-            5 => (eval(&items[0]) > eval(&items[1])) as i64,
-            6 => (eval(&items[0]) < eval(&items[1])) as i64,
-            7 => (eval(&items[0]) == eval(&items[1])) as i64,
-            ti => panic!("not implemented {}", ti),
-        },
+/// Evaluation errors carry the bit offset of the failing packet, if known.
+#[derive(Debug, PartialEq, Eq)]
+enum EvalError {
+    UnknownOperator {
+        type_id: i32,
+        offset: Option<usize>,
+    },
+    WrongOperandCount {
+        operator: String,
+        expected: &'static str,
+        found: usize,
+        offset: Option<usize>,
+    },
+    Overflow {
+        operator: String,
+        offset: Option<usize>,
+    },
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
     }
 }
 
+impl Error for EvalError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Precision {
+    /// Every intermediate value has to fit in an `i64`.
+    I64,
+    Arbitrary,
+}
+
+fn eval_with(packet: &Packet, precision: Precision) -> Result<BigUint, EvalError> {
+    let operator = || operator_name(packet.type_id);
+    let value = match &packet.content {
+        Content::Literal(value) => value.clone(),
+        Content::Subpackets(items) => {
+            let (expected, count_ok) = match packet.type_id {
+                0..=3 => ("at least 1 operand", !items.is_empty()),
+                5..=7 => ("exactly 2 operands", items.len() == 2),
+                type_id => {
+                    return Err(EvalError::UnknownOperator {
+                        type_id,
+                        offset: packet.offset,
+                    })
+                }
+            };
+            if !count_ok {
+                return Err(EvalError::WrongOperandCount {
+                    operator: operator(),
+                    expected,
+                    found: items.len(),
+                    offset: packet.offset,
+                });
+            }
+            let mut values = items
+                .iter()
+                .map(|item| eval_with(item, precision))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            let first = values.next().unwrap();
+            match packet.type_id {
+                0 => values.fold(first, |acc, x| acc + x),
+                1 => values.fold(first, |acc, x| acc * x),
+                2 => values.fold(first, |acc, x| acc.min(x)),
+                3 => values.fold(first, |acc, x| acc.max(x)),
+                type_id => {
+                    let second = values.next().unwrap();
+                    let holds = match type_id {
+                        5 => first > second,
+                        6 => first < second,
+                        _ => first == second,
+                    };
+                    BigUint::from(holds as u32)
+                }
+            }
+        }
+    };
+    if precision == Precision::I64 && value.bits() >= i64::BITS as u64 {
+        return Err(EvalError::Overflow {
+            operator: operator(),
+            offset: packet.offset,
+        });
+    }
+    Ok(value)
+}
+
+fn eval(packet: &Packet) -> Result<i64, EvalError> {
+    Ok(i64::try_from(&eval_with(packet, Precision::I64)?).unwrap())
+}
+
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let hex_input = reader.lines().next().ok_or(format!("empty input"))??;
-    let mut binary_input = hex_input
-        .chars()
-        .flat_map(|c| {
-            format!("{:04b}", i32::from_str_radix(&String::from(c), 16).unwrap())
-                .chars()
-                .collect::<Vec<_>>()
-        })
-        .collect::<BinaryStream>();
-
-    // println!("{:?}", binary_input);
-    let packet = read_packet(&mut binary_input)?;
-    // println!("{:?}", packet);
+    let flags = args().skip(2).collect::<Vec<_>>();
+    if flags.iter().any(|x| x == "--compile") {
+        let packet = compile(&hex_input)?;
+        println!("{}", packet);
+        println!("{}", encode_packet(&packet, LengthType::BitLength)?);
+        println!("Value: {}", eval_with(&packet, Precision::Arbitrary)?);
+        return Ok(());
+    }
+    let packet = read_packet(&mut BitReader::new(&hex_input))?;
+    if flags.iter().any(|x| x == "--dump") {
+        let annotations = Annotations {
+            versions: flags.iter().any(|x| x == "--versions"),
+            offsets: flags.iter().any(|x| x == "--offsets"),
+        };
+        println!("{}", packet.dump(annotations));
+    }
     println!("Task 1: {}", sum_version(&packet));
-    println!("Task 2: {}", eval(&packet));
+    if flags.iter().any(|x| x == "--exact") {
+        println!("Task 2: {}", eval_with(&packet, Precision::Arbitrary)?);
+    } else {
+        println!("Task 2: {}", eval(&packet)?);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+
+    fn decode(hex_input: &str) -> MyResult<Packet> {
+        read_packet(&mut BitReader::new(hex_input))
+    }
+
+    /// Deterministic packet trees for round-trip checks.
+    struct PacketGenerator(TestRng);
+
+    impl PacketGenerator {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0.below(bound)
+        }
+
+        fn packet(&mut self, depth: usize) -> Packet {
+            let version = self.next(8) as i32;
+            if depth == 0 || self.next(3) == 0 {
+                let n_bits = self.next(64);
+                let value = (0..self.next(3))
+                    .fold(BigUint::from(self.0.next_u64() >> n_bits), |acc, _| {
+                        (acc << 64u32) + self.0.next_u64()
+                    });
+                return Packet {
+                    version,
+                    type_id: 4,
+                    content: Content::Literal(value),
+                    offset: None,
+                };
+            }
+            let type_id = [0, 1, 2, 3, 5, 6, 7][self.next(7) as usize];
+            let n_subpackets = self.next(4) + 1;
+            Packet {
+                version,
+                type_id,
+                content: Content::Subpackets(
+                    (0..n_subpackets).map(|_| self.packet(depth - 1)).collect(),
+                ),
+                offset: None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_examples() -> MyResult<()> {
+        assert_eq!(
+            encode_packet(&decode("D2FE28")?, LengthType::BitLength)?,
+            "D2FE28"
+        );
+        assert_eq!(
+            encode_packet(&decode("38006F45291200")?, LengthType::BitLength)?,
+            "38006F45291200"
+        );
+        assert_eq!(
+            encode_packet(&decode("EE00D40C823060")?, LengthType::PacketCount)?,
+            "EE00D40C823060"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimal_literal_groups() -> MyResult<()> {
+        for (value, n_bits) in [(0u32, 11), (15, 11), (16, 16), (2021, 21)] {
+            let mut bits = Vec::new();
+            let packet = Packet {
+                version: 0,
+                type_id: 4,
+                content: Content::Literal(BigUint::from(value)),
+                offset: None,
+            };
+            write_packet(&mut bits, &packet, LengthType::BitLength)?;
+            assert_eq!(bits.len(), n_bits);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> MyResult<()> {
+        let mut generator = PacketGenerator(TestRng(2021));
+        for _ in 0..200 {
+            let packet = generator.packet(4);
+            for length_type in [LengthType::BitLength, LengthType::PacketCount] {
+                assert_eq!(decode(&encode_packet(&packet, length_type)?)?, packet);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_literal() -> MyResult<()> {
+        // 17 groups of 4 bits: one more than fits in an i64.
+        let packet = decode("D3FFFFFFFFFFFFFFFFFFFDE0")?;
+        assert_eq!(
+            packet.content,
+            Content::Literal((BigUint::from(1u32) << 68u32) - 1u32)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decode_error = |hex_input| {
+            *decode(hex_input)
+                .unwrap_err()
+                .downcast::<DecodeError>()
+                .unwrap()
+        };
+        // The body is announced as 26 bits, cutting the second literal short.
+        // This used to silently drop the subpacket.
+        assert_eq!(
+            decode_error("38006B45291200"),
+            DecodeError::UnexpectedEnd {
+                offset: 45,
+                wanted: 4
+            }
+        );
+        assert_eq!(
+            decode_error("D2FG28"),
+            DecodeError::InvalidHexDigit {
+                offset: 12,
+                digit: 'G'
+            }
+        );
+    }
+
+    #[test]
+    fn test_dump() -> MyResult<()> {
+        let packet = decode("9C0141080250320F1802104A08")?;
+        assert_eq!(
+            packet.to_string(),
+            "(eq (sum (lit 1) (lit 3)) (product (lit 2) (lit 2)))"
+        );
+        let packet = decode("38006F45291200")?;
+        let all = Annotations {
+            versions: true,
+            offsets: true,
+        };
+        assert_eq!(
+            packet.dump(all).to_string(),
+            "(lt v1 @0 (lit v6 @22 10) (lit v2 @33 20))"
+        );
+        let undecoded = Packet {
+            version: 6,
+            type_id: 4,
+            content: Content::Literal(BigUint::from(10u32)),
+            offset: None,
+        };
+        assert_eq!(undecoded.dump(all).to_string(), "(lit v6 10)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile() -> MyResult<()> {
+        let packet = compile("max(1, 2+3) == 5")?;
+        assert_eq!(
+            packet.to_string(),
+            "(eq (max (lit 1) (sum (lit 2) (lit 3))) (lit 5))"
+        );
+        assert_eq!(eval(&packet)?, 1);
+        let packet = compile("1 + 2 * 3 + (4 < 5) + min(7, 8, 9) * product(2)")?;
+        assert_eq!(eval(&packet)?, 1 + 2 * 3 + 1 + 7 * 2);
+        for length_type in [LengthType::BitLength, LengthType::PacketCount] {
+            let transmission = encode_packet(&packet, length_type)?;
+            assert_eq!(eval(&decode(&transmission)?)?, 22);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = compile("gt(1, 2, 3)").unwrap_err();
+        assert_eq!(
+            error.kind,
+            CompileErrorKind::WrongOperandCount {
+                operator: "gt",
+                expected: "exactly 2 operands",
+                found: 3
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "column 1: gt takes exactly 2 operands, got 3"
+        );
+        assert_eq!(
+            compile("1 + max()").unwrap_err().to_string(),
+            "column 5: max takes at least 1 operand, got 0"
+        );
+        assert_eq!(
+            compile("1 < 2 < 3").unwrap_err().kind,
+            CompileErrorKind::ChainedComparison
+        );
+        assert_eq!(
+            compile("avg(1)").unwrap_err().kind,
+            CompileErrorKind::UnknownFunction(String::from("avg"))
+        );
+        assert_eq!(
+            compile("3 - 1").unwrap_err(),
+            CompileError {
+                column: 3,
+                kind: CompileErrorKind::UnexpectedChar('-')
+            }
+        );
+        assert_eq!(
+            compile("(1 + 2").unwrap_err().to_string(),
+            "column 7: expected ')', found end of input"
+        );
+    }
+
+    #[test]
+    fn test_eval_examples() -> MyResult<()> {
+        for (transmission, value) in [
+            ("C200B40A82", 3),
+            ("04005AC33890", 54),
+            ("880086C3E88112", 7),
+            ("CE00C43D881120", 9),
+            ("D8005AC2A8F0", 1),
+            ("F600BC2D8F", 0),
+            ("9C005AC2F8F0", 0),
+            ("9C0141080250320F1802104A08", 1),
+        ] {
+            assert_eq!(eval(&decode(transmission)?)?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_errors() -> MyResult<()> {
+        assert_eq!(
+            eval(&compile("product(4294967296, 4294967296)")?),
+            Err(EvalError::Overflow {
+                operator: String::from("product"),
+                offset: None
+            })
+        );
+        let wide = compile("max(9223372036854775808, 1) > 2")?;
+        assert!(matches!(eval(&wide), Err(EvalError::Overflow { .. })));
+        assert_eq!(eval_with(&wide, Precision::Arbitrary)?, BigUint::from(1u32));
+        assert_eq!(
+            eval_with(
+                &compile("product(4294967296, 4294967296)")?,
+                Precision::Arbitrary
+            )?,
+            BigUint::from(1u32) << 64u32
+        );
+
+        // gt(1, 2, 3), bypassing the compiler's operand check.
+        let mut three_operands = compile("gt(1, 2)")?;
+        if let Content::Subpackets(items) = &mut three_operands.content {
+            items.push(items[0].clone());
+        }
+        let transmission = encode_packet(&three_operands, LengthType::PacketCount)?;
+        assert_eq!(
+            eval(&decode(&transmission)?),
+            Err(EvalError::WrongOperandCount {
+                operator: String::from("gt"),
+                expected: "exactly 2 operands",
+                found: 3,
+                offset: Some(0)
+            })
+        );
+        let unknown = Packet {
+            type_id: 4,
+            ..compile("min(1)")?
+        };
+        assert_eq!(
+            eval(&unknown),
+            Err(EvalError::UnknownOperator {
+                type_id: 4,
+                offset: None
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let literal = |value: u32| Packet {
+            version: 0,
+            type_id: 4,
+            content: Content::Literal(BigUint::from(value)),
+            offset: None,
+        };
+        let bad_version = Packet {
+            version: 8,
+            ..literal(1)
+        };
+        assert!(encode_packet(&bad_version, LengthType::BitLength).is_err());
+        let too_many = Packet {
+            version: 0,
+            type_id: 0,
+            content: Content::Subpackets(vec![literal(1); 2048]),
+            offset: None,
+        };
+        assert!(encode_packet(&too_many, LengthType::PacketCount).is_err());
+        let mislabelled = Packet {
+            version: 0,
+            type_id: 4,
+            content: Content::Subpackets(vec![]),
+            offset: None,
+        };
+        assert!(encode_packet(&mislabelled, LengthType::BitLength).is_err());
+    }
+}
@@ -1,48 +1,365 @@
 use crate::MyResult;
-use itertools::iterate;
-use itertools::Itertools;
 use regex::Regex;
+use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
 use std::io::BufRead;
 use std::ops::RangeInclusive;
 use std::str::FromStr;
 
-#[derive(Clone, Copy)]
-struct State {
-    x: i32,
-    y: i32,
-    vx: i32,
-    vy: i32,
-    my: i32,
-}
-
-fn simulate(
-    ivx: i32,
-    ivy: i32,
-    x_target: &RangeInclusive<i32>,
-    y_target: &RangeInclusive<i32>,
-) -> Option<i32> {
-    let step = |&State { x, y, vx, vy, my }: &State| State {
-        x: x + vx,
-        y: y + vy,
-        vx: (vx - 1).max(0),
-        vy: vy - 1,
-        my: y.max(my),
+#[derive(Debug, PartialEq, Eq)]
+pub enum LaunchError {
+    EmptyTarget,
+    /// The probe can hang still inside the target's x range while the target
+    /// also spans y = 0, which every upward launch passes through again.
+    InfinitelyManyLaunches,
+}
+
+impl Display for LaunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for LaunchError {}
+
+/// Steps (counted from 1) during which the probe is within range on one
+/// axis. `None` as the end means forever.
+type StepRange = (i64, Option<i64>);
+
+/// Positions are worked out in `i128` and saturate at the `i64` bounds, so
+/// far launches compare correctly with any target.
+fn saturate(position: i128) -> i64 {
+    position.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+fn x_after(vx: i64, t: i64) -> i64 {
+    let (sign, speed) = (vx.signum() as i128, (vx as i128).abs());
+    let t = (t as i128).min(speed);
+    saturate(sign * (speed * t - t * (t - 1) / 2))
+}
+
+fn y_after(vy: i64, t: i64) -> i64 {
+    let (vy, t) = (vy as i128, t as i128);
+    saturate(vy * t - t * (t - 1) / 2)
+}
+
+/// The first `t` in `lo..=hi` for which `pred` holds, given that `pred` is
+/// monotone (false up to some point, true afterwards); `hi + 1` if none.
+fn first_true(lo: i64, hi: i64, pred: impl Fn(i64) -> bool) -> i64 {
+    let (mut lo, mut hi) = (lo, hi + 1);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    lo
+}
+
+/// Like `first_true`, but without an upper bound: doubles the search window
+/// until `pred` holds, which the caller guarantees will eventually happen.
+fn first_true_unbounded(lo: i64, pred: impl Fn(i64) -> bool) -> i64 {
+    let mut width = 1;
+    while !pred(lo + width) {
+        width *= 2;
+    }
+    first_true(lo, lo + width, pred)
+}
+
+fn x_steps(vx: i64, target: &RangeInclusive<i64>) -> Option<StepRange> {
+    // Mirror launches to the left so that x grows with time.
+    let (speed, x1, x2) = if vx < 0 {
+        (-vx, -target.end(), -target.start())
+    } else {
+        (vx, *target.start(), *target.end())
     };
-    iterate(
-        State {
-            x: 0,
-            y: 0,
-            vx: ivx,
-            vy: ivy,
-            my: 0,
-        },
-        step,
-    )
-    .take_while(|&State { x, y, .. }| x <= *x_target.end() && y >= *y_target.start())
-    .filter(|State { x, y, .. }| x_target.contains(&x) && y_target.contains(&y))
-    // .inspect(|t| println!("{:?}", t))
-    .map(|t| t.my)
-    .next()
+    let resting_x = x_after(speed, speed);
+    if resting_x < x1 {
+        return None;
+    }
+    let first = first_true(1, speed.max(1), |t| x_after(speed, t) >= x1);
+    let last = if resting_x <= x2 {
+        None
+    } else {
+        Some(first_true(0, speed, |t| x_after(speed, t) > x2) - 1)
+    };
+    match last {
+        Some(last) if last < first => None,
+        _ => Some((first, last)),
+    }
+}
+
+/// The height rises while `t <= vy` and falls afterwards, so there can be one
+/// range of steps on the way up and one on the way down.
+fn y_steps(vy: i64, target: &RangeInclusive<i64>) -> Vec<StepRange> {
+    let (y1, y2) = (*target.start(), *target.end());
+    let mut result = Vec::new();
+    if vy >= 1 {
+        let first = first_true(1, vy, |t| y_after(vy, t) >= y1);
+        let last = first_true(1, vy, |t| y_after(vy, t) > y2) - 1;
+        if first <= last {
+            result.push((first, Some(last)));
+        }
+    }
+    let falling_from = vy.max(0) + 1;
+    let first = first_true_unbounded(falling_from - 1, |t| {
+        t >= falling_from && y_after(vy, t) <= y2
+    });
+    let last = first_true_unbounded(first - 1, |t| t >= first && y_after(vy, t) < y1) - 1;
+    if first <= last {
+        result.push((first, Some(last)));
+    }
+    result
+}
+
+/// Horizontal speeds that put the probe within `a..=b`, with `a >= 1`, on
+/// step `t`. At a fixed step the position grows with the speed.
+fn speeds_at_step(a: i64, b: i64, t: i64) -> RangeInclusive<i64> {
+    first_true(1, a, |s| x_after(s, t) >= a)..=first_true(1, b, |s| x_after(s, t) > b) - 1
+}
+
+/// The slowest horizontal speed that carries the probe beyond `x >= 0`.
+fn slowest_past(x: i64) -> i64 {
+    first_true_unbounded(0, |s| x_after(s, s) > x)
+}
+
+fn first_common_step((a1, a2): StepRange, (b1, b2): StepRange) -> Option<i64> {
+    let first = a1.max(b1);
+    let last = match (a2, b2) {
+        (Some(a2), Some(b2)) => a2.min(b2),
+        (Some(last), None) | (None, Some(last)) => last,
+        (None, None) => return Some(first),
+    };
+    (first <= last).then_some(first)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Launch {
+    pub vx: i64,
+    pub vy: i64,
+    pub first_hit: i64,
+}
+
+impl Launch {
+    /// Highest point reached up to and including the first step in the
+    /// target, counting the launch position.
+    pub fn peak_height(&self) -> i64 {
+        y_after(self.vy, self.first_hit.min(self.vy.max(0)))
+    }
+
+    /// Positions from the launch until the probe is past the target, ending
+    /// with the first one beyond its far edge or below it while falling.
+    pub fn trajectory(
+        &self,
+        x_target: &RangeInclusive<i64>,
+        y_target: &RangeInclusive<i64>,
+    ) -> Vec<(i64, i64)> {
+        let mut points = Vec::new();
+        for t in 0.. {
+            let point = (x_after(self.vx, t), y_after(self.vy, t));
+            points.push(point);
+            // After the first hit x only moves away from the launch, so
+            // leaving the x range means passing the far edge; y can only get
+            // below the target by falling.
+            if t > self.first_hit && (!x_target.contains(&point.0) || point.1 < *y_target.start()) {
+                break;
+            }
+        }
+        points
+    }
+}
+
+/// Every initial velocity that puts the probe inside the target after some
+/// step. Vertical speeds are bounded by the target's heights: faster ones
+/// overshoot it on the first step up or down. Only a target spanning y = 0
+/// also needs the horizontal speeds, as upward launches come back through
+/// it. For each vertical speed, the horizontal ones are found step by step
+/// while the probe is level with the target, so the work does not depend on
+/// how far away the target is.
+pub fn valid_launches(
+    x_target: &RangeInclusive<i64>,
+    y_target: &RangeInclusive<i64>,
+) -> Result<Vec<Launch>, LaunchError> {
+    if x_target.is_empty() || y_target.is_empty() {
+        return Err(LaunchError::EmptyTarget);
+    }
+    let (x1, x2) = (*x_target.start(), *x_target.end());
+    let (y1, y2) = (*y_target.start(), *y_target.end());
+    // The near and far edge of the target on each side of the launch, as
+    // distances, with launches to the left negated.
+    let sides = [
+        (1, (x2 >= 1).then(|| (x1.max(1), x2))),
+        (-1, (x1 <= -1).then(|| ((-x2).max(1), -x1))),
+    ];
+
+    let mut max_vy = y1.abs().max(y2.abs());
+    if y_target.contains(&0) {
+        let comes_to_rest = x_target.contains(&0)
+            || sides.iter().filter_map(|&(_, side)| side).any(|(a, b)| {
+                let speed = slowest_past(a - 1);
+                x_after(speed, speed) <= b
+            });
+        if comes_to_rest {
+            return Err(LaunchError::InfinitelyManyLaunches);
+        }
+        // An upward launch is back at y = 0 on step 2 * vy + 1. A probe that
+        // leaves the x range does so in fewer steps than its speed, and
+        // faster ones leave sooner, so none is inside it after
+        // `slowest_past(far_edge)` steps.
+        let far_edge = sides
+            .iter()
+            .filter_map(|&(_, side)| Some(side?.1))
+            .max()
+            .unwrap_or(0);
+        max_vy = max_vy.max(slowest_past(far_edge));
+    }
+
+    let mut launches = Vec::new();
+    for vy in y1.min(0)..=max_vy {
+        let y_ranges = y_steps(vy, y_target);
+        let mut vx_candidates = Vec::new();
+        if x_target.contains(&0) {
+            vx_candidates.push(0);
+        }
+        for &(first, last) in &y_ranges {
+            // Heights always leave the target, so y ranges end.
+            for t in first..=last.unwrap_or(first) {
+                for &(sign, side) in &sides {
+                    if let Some((a, b)) = side {
+                        vx_candidates.extend(speeds_at_step(a, b, t).map(|s| sign * s));
+                    }
+                }
+            }
+        }
+        vx_candidates.sort_unstable();
+        vx_candidates.dedup();
+        for vx in vx_candidates {
+            let x_range = match x_steps(vx, x_target) {
+                Some(x_range) => x_range,
+                None => continue,
+            };
+            let first_hit = y_ranges
+                .iter()
+                .filter_map(|&y_range| first_common_step(x_range, y_range))
+                .min();
+            if let Some(first_hit) = first_hit {
+                launches.push(Launch { vx, vy, first_hit });
+            }
+        }
+    }
+    Ok(launches)
+}
+
+pub struct Trajectory {
+    pub launch: Launch,
+    pub points: Vec<(i64, i64)>,
+}
+
+impl Trajectory {
+    /// Steps until the probe is past the target.
+    pub fn flight_steps(&self) -> usize {
+        self.points.len() - 1
+    }
+}
+
+pub struct Trajectories {
+    x_target: RangeInclusive<i64>,
+    y_target: RangeInclusive<i64>,
+    items: Vec<Trajectory>,
+}
+
+impl Trajectories {
+    pub fn new(
+        x_target: RangeInclusive<i64>,
+        y_target: RangeInclusive<i64>,
+    ) -> Result<Trajectories, LaunchError> {
+        let items = valid_launches(&x_target, &y_target)?
+            .into_iter()
+            .map(|launch| Trajectory {
+                points: launch.trajectory(&x_target, &y_target),
+                launch,
+            })
+            .collect();
+        Ok(Trajectories {
+            x_target,
+            y_target,
+            items,
+        })
+    }
+
+    pub fn items(&self) -> &[Trajectory] {
+        &self.items
+    }
+
+    pub fn highest_apex(&self) -> Option<&Trajectory> {
+        self.items.iter().max_by_key(|x| x.launch.peak_height())
+    }
+
+    pub fn fewest_steps(&self) -> Option<&Trajectory> {
+        self.items.iter().min_by_key(|x| x.launch.first_hit)
+    }
+
+    pub fn longest_flight(&self) -> Option<&Trajectory> {
+        self.items.iter().max_by_key(|x| x.flight_steps())
+    }
+
+    /// One row per trajectory point.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("vx,vy,step,x,y\n");
+        for Trajectory { launch, points } in &self.items {
+            for (step, (x, y)) in points.iter().enumerate() {
+                csv += &format!("{},{},{},{},{}\n", launch.vx, launch.vy, step, x, y);
+            }
+        }
+        csv
+    }
+
+    /// Draws the target area and every trajectory as a polyline, with y
+    /// pointing up.
+    pub fn to_svg(&self) -> String {
+        let (x1, x2) = (*self.x_target.start(), *self.x_target.end());
+        let (y1, y2) = (*self.y_target.start(), *self.y_target.end());
+        let all_points = self.items.iter().flat_map(|x| x.points.iter());
+        let (mut min_x, mut max_x, mut min_y, mut max_y) =
+            (x1.min(0), x2.max(0), y1.min(0), y2.max(0));
+        for &(x, y) in all_points {
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+        }
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+            min_x - 1,
+            -max_y - 1,
+            max_x - min_x + 2,
+            max_y - min_y + 2
+        );
+        svg += &format!(
+            "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#ccc\"/>\n",
+            x1,
+            -y2,
+            x2 - x1,
+            y2 - y1
+        );
+        for Trajectory { launch, points } in &self.items {
+            let points = points
+                .iter()
+                .map(|(x, y)| format!("{},{}", x, -y))
+                .collect::<Vec<_>>()
+                .join(" ");
+            svg += &format!(
+                "  <polyline points=\"{}\" fill=\"none\" stroke=\"#06c\" \
+                 vector-effect=\"non-scaling-stroke\"><title>{},{}</title></polyline>\n",
+                points, launch.vx, launch.vy
+            );
+        }
+        svg += "</svg>\n";
+        svg
+    }
 }
 
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
@@ -51,25 +368,217 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let captures = regex
         .captures(&input_line)
         .ok_or(format!("no match {}", input_line))?;
-    let read = |i| i32::from_str(&captures[i]);
+    let read = |i| i64::from_str(&captures[i]);
     let x_target = read(1)?..=read(2)?;
     let y_target = read(3)?..=read(4)?;
     println!("{:?} {:?}", x_target, y_target);
 
-    let my = (0..1000)
-        .cartesian_product(0..1000)
-        .filter_map(|(ivx, ivy)| simulate(ivx, ivy, &x_target, &y_target))
-        .max();
+    let trajectories = Trajectories::new(x_target, y_target)?;
+
+    println!(
+        "Task 1: {:?}",
+        trajectories.highest_apex().map(|x| x.launch.peak_height())
+    );
+    println!("Task 2: {:?}", trajectories.items().len());
+    let export = match args().nth(2).as_deref() {
+        Some("--csv") => Some(trajectories.to_csv()),
+        Some("--svg") => Some(trajectories.to_svg()),
+        _ => None,
+    };
+    if let Some(contents) = export {
+        let path = args().nth(3).ok_or("export needs an output file")?;
+        std::fs::write(&path, contents)?;
+        println!("Wrote {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+    use std::collections::HashSet;
 
-    println!("Task 1: {:?}", my);
+    fn brute_force(
+        x_target: &RangeInclusive<i64>,
+        y_target: &RangeInclusive<i64>,
+        window: i64,
+    ) -> HashSet<(i64, i64)> {
+        let mut result = HashSet::new();
+        for vx in -window..=window {
+            for vy in -window..=window {
+                // Stop once the probe falls below the target.
+                let hit = (1..4 * window)
+                    .take_while(|&t| t <= vy || y_after(vy, t) >= *y_target.start())
+                    .any(|t| {
+                        x_target.contains(&x_after(vx, t)) && y_target.contains(&y_after(vy, t))
+                    });
+                if hit {
+                    result.insert((vx, vy));
+                }
+            }
+        }
+        result
+    }
 
-    let nys = (-1000..1000)
-        .cartesian_product(-1000..1000)
-        .filter_map(|(ivx, ivy)| simulate(ivx, ivy, &x_target, &y_target))
-        .count();
+    #[test]
+    fn test_example() -> MyResult<()> {
+        let launches = valid_launches(&(20..=30), &(-10..=-5))?;
+        assert_eq!(launches.len(), 112);
+        assert_eq!(launches.iter().map(Launch::peak_height).max(), Some(45));
+        Ok(())
+    }
 
-    println!("Task 2: {:?}", nys);
+    #[test]
+    fn test_matches_brute_force() -> MyResult<()> {
+        let mut targets = vec![
+            (20..=30, -10..=-5),
+            (-30..=-20, -10..=-5),
+            (-4..=6, -10..=-5),
+            (20..=30, 5..=10),
+            (-30..=-20, 3..=9),
+            (7..=9, -6..=8),
+        ];
+        let mut rng = TestRng(17);
+        let mut range = || {
+            let start = rng.below(40) as i64 - 20;
+            start..=start + rng.below(10) as i64
+        };
+        targets.extend((0..100).map(|_| (range(), range())));
+        for (x_target, y_target) in targets {
+            let launches = match valid_launches(&x_target, &y_target) {
+                Err(LaunchError::InfinitelyManyLaunches) => continue,
+                launches => launches?,
+            };
+            let velocities = launches
+                .iter()
+                .map(|l| (l.vx, l.vy))
+                .collect::<HashSet<_>>();
+            assert_eq!(velocities.len(), launches.len());
+            assert_eq!(
+                velocities,
+                brute_force(&x_target, &y_target, 40),
+                "{:?} {:?}",
+                x_target,
+                y_target
+            );
+            for launch in launches {
+                assert!(x_target.contains(&x_after(launch.vx, launch.first_hit)));
+                assert!(y_target.contains(&y_after(launch.vy, launch.first_hit)));
+            }
+        }
+        Ok(())
+    }
 
-    // println!("{:?}", simulate(6, 9, &x_target, &y_target));
-    Ok(())
+    #[test]
+    fn test_far_target() -> MyResult<()> {
+        let x_far = i64::MAX - 20;
+        for (x_target, y_target) in [
+            (x_far..=x_far + 10, -10..=-5),
+            (-x_far - 10..=-x_far, -10..=-5),
+            (x_far..=x_far + 10, 5..=10),
+        ] {
+            let launches = valid_launches(&x_target, &y_target)?;
+            // At least every direct shot on the first step.
+            assert!(launches.len() >= 66);
+            for launch in launches {
+                let inside = |t| {
+                    x_target.contains(&x_after(launch.vx, t))
+                        && y_target.contains(&y_after(launch.vy, t))
+                };
+                assert!(inside(launch.first_hit), "{:?}", launch);
+                assert!(!(1..launch.first_hit).any(inside), "{:?}", launch);
+            }
+        }
+        assert_eq!(x_after(i64::MAX, 3), i64::MAX);
+        assert_eq!(x_after(i64::MIN + 1, 3), i64::MIN);
+        assert_eq!(y_after(-1, i64::MAX), i64::MIN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trajectories() -> MyResult<()> {
+        let trajectories = Trajectories::new(20..=30, -10..=-5)?;
+        let highest = trajectories.highest_apex().unwrap();
+        assert_eq!(highest.launch.vy, 9);
+        assert_eq!(highest.points.iter().map(|x| x.1).max(), Some(45));
+        let n_points = highest.points.len();
+        assert_eq!(highest.points[n_points - 2].1, -10);
+        assert_eq!(highest.points[n_points - 1].1, -21);
+
+        let fewest = trajectories.fewest_steps().unwrap();
+        assert_eq!(fewest.launch.first_hit, 1);
+        assert_eq!(fewest.points, [(0, 0), (20, -10), (39, -21)]);
+        let longest = trajectories.longest_flight().unwrap();
+        assert_eq!(longest.launch.first_hit, 20);
+        assert_eq!(longest.flight_steps(), 21);
+
+        let csv = trajectories.to_csv();
+        assert!(csv.starts_with("vx,vy,step,x,y\n"));
+        assert!(csv.contains("6,9,20,21,-10\n"));
+        assert_eq!(
+            csv.lines().count(),
+            1 + trajectories
+                .items()
+                .iter()
+                .map(|x| x.points.len())
+                .sum::<usize>()
+        );
+
+        let svg = trajectories.to_svg();
+        assert!(
+            svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"-1 -46 61 68\">")
+        );
+        assert!(svg.contains("<rect x=\"20\" y=\"5\" width=\"10\" height=\"5\""));
+        assert_eq!(svg.matches("<polyline").count(), 112);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flight_past_target() -> MyResult<()> {
+        let (x_target, y_target) = (20..=30, -30..=-5);
+        let launch = valid_launches(&x_target, &y_target)?
+            .into_iter()
+            .find(|x| (x.vx, x.vy) == (7, -1))
+            .unwrap();
+        assert_eq!(launch.first_hit, 4);
+        let points = launch.trajectory(&x_target, &y_target);
+        // Inside from step 4 to 7, then below the target.
+        let inside = points
+            .iter()
+            .filter(|(x, y)| x_target.contains(x) && y_target.contains(y))
+            .count();
+        assert_eq!(inside, 4);
+        assert_eq!(points.len(), 9);
+        assert_eq!(points.last(), Some(&(28, -36)));
+        let longest = Trajectories::new(x_target, y_target)?
+            .longest_flight()
+            .unwrap()
+            .flight_steps();
+        assert_eq!(longest, 61);
+
+        // This one leaves past the far edge instead.
+        let launch = Launch {
+            vx: 7,
+            vy: -1,
+            first_hit: 1,
+        };
+        assert_eq!(
+            launch.trajectory(&(5..=7), &(-10..=-1)),
+            [(0, 0), (7, -1), (13, -3)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_infinite() {
+        assert_eq!(
+            valid_launches(&(5..=7), &(-3..=2)),
+            Err(LaunchError::InfinitelyManyLaunches)
+        );
+        assert_eq!(
+            valid_launches(&(5..=7), &RangeInclusive::new(3, 2)),
+            Err(LaunchError::EmptyTarget)
+        );
+    }
 }
@@ -2,8 +2,12 @@ use itertools::Itertools;
 
 use crate::day_18::SnailFish::{Pair, Regular};
 use crate::MyResult;
+use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
 use std::io::BufRead;
 use std::mem::replace;
+use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum SnailFish {
@@ -58,21 +62,34 @@ impl SnailFish {
     }
 
     fn explode(&mut self) -> bool {
-        let mut path = Vec::new();
-        if let Some(([l, r], path)) = self.find_explode_node(&mut path) {
-            if let Some(l_path) = prepare_path(path.clone(), 0) {
-                self.walk_path_and_add(&l_path, l);
-            }
-            if let Some(r_path) = prepare_path(path, 1) {
-                self.walk_path_and_add(&r_path, r);
-            }
-            true
-        } else {
-            false
+        self.explode_traced().is_some()
+    }
+
+    fn explode_traced(&mut self) -> Option<ReductionAction> {
+        let ([l, r], path) = self.find_explode_node(&mut Vec::new())?;
+        let mut pushed_left = None;
+        if let Some(l_path) = prepare_path(path.clone(), 0) {
+            self.walk_path_and_add(&l_path, l);
+            pushed_left = Some(l);
         }
+        let mut pushed_right = None;
+        if let Some(r_path) = prepare_path(path.clone(), 1) {
+            self.walk_path_and_add(&r_path, r);
+            pushed_right = Some(r);
+        }
+        Some(ReductionAction::Explode {
+            path,
+            pair: [l, r],
+            pushed_left,
+            pushed_right,
+        })
     }
 
     fn split(&mut self) -> bool {
+        self.split_traced(&mut Vec::new()).is_some()
+    }
+
+    fn split_traced(&mut self, path: &mut Vec<i32>) -> Option<ReductionAction> {
         match self {
             Regular(num) if *num >= 10 => {
                 let num = *num;
@@ -80,32 +97,41 @@ impl SnailFish {
                     self,
                     SnailFish::pair(Regular(num / 2), Regular((num + 1) / 2)),
                 ));
-                true
+                Some(ReductionAction::Split {
+                    path: path.clone(),
+                    value: num,
+                })
             }
-            Regular(..) => false,
+            Regular(..) => None,
             Pair(children) => {
-                for child in children {
-                    if child.split() {
-                        return true;
+                for (dir, child) in children.iter_mut().enumerate() {
+                    path.push(dir as i32);
+                    let action = child.split_traced(path);
+                    path.pop();
+                    if action.is_some() {
+                        return action;
                     }
                 }
-                false
+                None
             }
         }
     }
 
-    fn reduce(&mut self) {
-        loop {
-            if self.explode() {
-                continue;
-            }
-            if self.split() {
-                continue;
-            }
-            break;
+    /// Reduces like `reduce`, calling `on_step` with each action and the
+    /// number it produced.
+    fn reduce_with(&mut self, mut on_step: impl FnMut(ReductionAction, &SnailFish)) {
+        while let Some(action) = self
+            .explode_traced()
+            .or_else(|| self.split_traced(&mut Vec::new()))
+        {
+            on_step(action, self);
         }
     }
 
+    fn reduce(&mut self) {
+        self.reduce_with(|_, _| ());
+    }
+
     fn compute_magnitude(&self) -> i32 {
         match self {
             Regular(num) => *num,
@@ -118,6 +144,131 @@ impl SnailFish {
         sum.reduce();
         return sum;
     }
+
+    fn add_traced(self, rh: SnailFish) -> (SnailFish, ReductionTrace) {
+        let mut sum = SnailFish::pair(self, rh);
+        let mut trace = ReductionTrace {
+            start: sum.clone(),
+            steps: Vec::new(),
+        };
+        sum.reduce_with(|action, number| trace.steps.push((action, number.clone())));
+        (sum, trace)
+    }
+}
+
+/// Paths are the child indices from the root, 0 for left and 1 for right.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum ReductionAction {
+    /// `pushed_left`/`pushed_right` are `None` when there was no regular
+    /// number on that side and the value was dropped.
+    Explode {
+        path: Vec<i32>,
+        pair: [i32; 2],
+        pushed_left: Option<i32>,
+        pushed_right: Option<i32>,
+    },
+    Split {
+        path: Vec<i32>,
+        value: i32,
+    },
+}
+
+fn path_to_string(path: &[i32]) -> String {
+    path.iter()
+        .map(|&dir| if dir == 0 { 'L' } else { 'R' })
+        .collect()
+}
+
+fn option_to_json(value: Option<i32>) -> String {
+    value.map_or_else(|| "null".to_string(), |x| x.to_string())
+}
+
+impl ReductionAction {
+    /// The action as a JSON object, with `result` as the number it left.
+    /// A snailfish number prints as a valid nested JSON array, so numbers
+    /// are embedded as they are.
+    fn to_json(&self, result: &SnailFish) -> String {
+        match self {
+            ReductionAction::Explode {
+                path,
+                pair: [l, r],
+                pushed_left,
+                pushed_right,
+            } => format!(
+                "{{\"action\":\"explode\",\"position\":\"{}\",\"pair\":[{},{}],\"pushed_left\":{},\"pushed_right\":{},\"result\":{}}}",
+                path_to_string(path),
+                l,
+                r,
+                option_to_json(*pushed_left),
+                option_to_json(*pushed_right),
+                result
+            ),
+            ReductionAction::Split { path, value } => format!(
+                "{{\"action\":\"split\",\"position\":\"{}\",\"value\":{},\"result\":{}}}",
+                path_to_string(path),
+                value,
+                result
+            ),
+        }
+    }
+}
+
+impl Display for ReductionAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let pushed = |value: &Option<i32>, side, x| match value {
+            Some(x) => format!("{} pushed {}", x, side),
+            None => format!("{} dropped", x),
+        };
+        match self {
+            ReductionAction::Explode {
+                path,
+                pair: [l, r],
+                pushed_left,
+                pushed_right,
+            } => write!(
+                f,
+                "explode [{},{}] at {}: {}, {}",
+                l,
+                r,
+                path_to_string(path),
+                pushed(pushed_left, "left", l),
+                pushed(pushed_right, "right", r)
+            ),
+            ReductionAction::Split { path, value } => {
+                write!(f, "split {} at {}", value, path_to_string(path))
+            }
+        }
+    }
+}
+
+/// Every action taken while reducing `start`, each with the number right
+/// after it.
+#[derive(Debug, Clone)]
+struct ReductionTrace {
+    start: SnailFish,
+    steps: Vec<(ReductionAction, SnailFish)>,
+}
+
+impl ReductionTrace {
+    fn to_json(&self) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .map(|(action, number)| action.to_json(number))
+            .join(",");
+        format!("{{\"start\":{},\"steps\":[{}]}}", self.start, steps)
+    }
+}
+
+impl Display for ReductionTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(f, "start: {}", self.start)?;
+        for (step_no, (action, number)) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", step_no + 1, action)?;
+            writeln!(f, "     -> {}", number)?;
+        }
+        Ok(())
+    }
 }
 
 fn prepare_path(mut path: Vec<i32>, current_dir: i32) -> Option<Vec<i32>> {
@@ -136,55 +287,292 @@ fn prepare_path(mut path: Vec<i32>, current_dir: i32) -> Option<Vec<i32>> {
 }
 
 fn read_snail_fish_full(input: &str) -> MyResult<(SnailFish, &str)> {
-    match input.chars().next().ok_or(format!("unexpected end"))? {
-        c @ ('0'..='9') => Ok((Regular(c as i32 - '0' as i32), &input[1..])),
+    let input = input.trim_start();
+    match input.chars().next().ok_or("unexpected end")? {
+        '0'..='9' => {
+            let digits_end = input
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(input.len());
+            Ok((Regular(input[..digits_end].parse()?), &input[digits_end..]))
+        }
         '[' => {
             let (first_part, rest) = read_snail_fish_full(&input[1..])?;
-            let mid_char = rest.chars().next().ok_or(format!("unexpected end mid"))?;
+            let rest = rest.trim_start();
+            let mid_char = rest.chars().next().ok_or("unexpected end mid")?;
             if mid_char != ',' {
                 Err(format!("unexpected mid char {}", mid_char))?
             }
             let (second_part, rest) = read_snail_fish_full(&rest[1..])?;
-            let end_char = rest.chars().next().ok_or(format!("unexpected end last"))?;
+            let rest = rest.trim_start();
+            let end_char = rest.chars().next().ok_or("unexpected end last")?;
             if end_char != ']' {
                 Err(format!("unexpected end char {}", end_char))?
             }
             Ok((SnailFish::pair(first_part, second_part), &rest[1..]))
         }
-        c @ _ => Err(format!("unexpected start char {}", c))?,
+        c => Err(format!("unexpected start char {}", c))?,
     }
 }
 
 fn read_snail_fish(input: &str) -> MyResult<SnailFish> {
     let (sf, rest) = read_snail_fish_full(input)?;
-    if rest != "" {
+    if !rest.trim().is_empty() {
         Err(format!("unexpected rest {}", rest))?
     }
     Ok(sf)
 }
 
+impl FromStr for SnailFish {
+    type Err = Box<dyn Error>;
+    fn from_str(input: &str) -> MyResult<SnailFish> {
+        read_snail_fish(input)
+    }
+}
+
+impl Display for SnailFish {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Regular(num) => write!(f, "{}", num),
+            Pair([l, r]) => write!(f, "[{},{}]", l, r),
+        }
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug)]
+struct FlatNode {
+    depth: u32,
+    value: i32,
+    prev: usize,
+    next: usize,
+}
+
+/// The regular numbers of a snailfish number from left to right, each with
+/// its nesting depth. They form a doubly linked list inside one arena, so an
+/// explode or a split only relinks neighbours; slots of exploded pairs are
+/// reused by later splits.
+#[derive(Clone, Debug)]
+struct FlatSnailFish {
+    nodes: Vec<FlatNode>,
+    head: usize,
+    free: Vec<usize>,
+}
+
+impl FlatSnailFish {
+    fn from_leaves(leaves: impl IntoIterator<Item = (u32, i32)>) -> FlatSnailFish {
+        let mut nodes = leaves
+            .into_iter()
+            .enumerate()
+            .map(|(i, (depth, value))| FlatNode {
+                depth,
+                value,
+                prev: i.wrapping_sub(1),
+                next: i + 1,
+            })
+            .collect::<Vec<_>>();
+        if let Some(last) = nodes.last_mut() {
+            last.next = NIL;
+        }
+        let head = if nodes.is_empty() { NIL } else { 0 };
+        FlatSnailFish {
+            nodes,
+            head,
+            free: Vec::new(),
+        }
+    }
+
+    /// Fails for numbers nested deeper than 4 levels, which `reduce` cannot
+    /// take as an operand.
+    fn from_tree(tree: &SnailFish) -> MyResult<FlatSnailFish> {
+        fn collect(tree: &SnailFish, depth: u32, leaves: &mut Vec<(u32, i32)>) {
+            match tree {
+                Regular(num) => leaves.push((depth, *num)),
+                Pair([l, r]) => {
+                    collect(l, depth + 1, leaves);
+                    collect(r, depth + 1, leaves);
+                }
+            }
+        }
+        let mut leaves = Vec::new();
+        collect(tree, 0, &mut leaves);
+        if leaves.iter().any(|&(depth, _)| depth > 4) {
+            Err(format!("{} is nested deeper than 4 pairs", tree))?
+        }
+        Ok(FlatSnailFish::from_leaves(leaves))
+    }
+
+    fn leaves(&self) -> impl Iterator<Item = (u32, i32)> + '_ {
+        let mut current = self.head;
+        std::iter::from_fn(move || {
+            let node = self.nodes.get(current)?;
+            current = node.next;
+            Some((node.depth, node.value))
+        })
+    }
+
+    /// Rebuilds pairs bottom-up: two adjacent entries of the same depth are
+    /// always the two halves of one pair.
+    fn fold_pairs<T>(&self, leaf: impl Fn(i32) -> T, pair: impl Fn(T, T) -> T) -> T {
+        let mut stack = Vec::<(u32, T)>::new();
+        for (depth, value) in self.leaves() {
+            let mut top = (depth, leaf(value));
+            while stack.last().map(|x| x.0) == Some(top.0) {
+                let (depth, l) = stack.pop().unwrap();
+                top = (depth - 1, pair(l, top.1));
+            }
+            stack.push(top);
+        }
+        stack.pop().unwrap().1
+    }
+
+    fn to_tree(&self) -> SnailFish {
+        self.fold_pairs(Regular, SnailFish::pair)
+    }
+
+    fn compute_magnitude(&self) -> i32 {
+        self.fold_pairs(|x| x, |l, r| 3 * l + 2 * r)
+    }
+
+    /// Merges the pair whose left half is `left` into a 0 one level up and
+    /// returns that node.
+    fn explode(&mut self, left: usize) -> usize {
+        let FlatNode {
+            value: l,
+            prev,
+            next: right,
+            ..
+        } = self.nodes[left];
+        let FlatNode { value: r, next, .. } = self.nodes[right];
+        if prev != NIL {
+            self.nodes[prev].value += l;
+        }
+        if next != NIL {
+            self.nodes[next].value += r;
+            self.nodes[next].prev = left;
+        }
+        let node = &mut self.nodes[left];
+        node.value = 0;
+        node.depth -= 1;
+        node.next = next;
+        self.free.push(right);
+        left
+    }
+
+    /// Replaces `at` by a pair of its halves and returns the left one.
+    fn split(&mut self, at: usize) -> usize {
+        let FlatNode {
+            depth, value, next, ..
+        } = self.nodes[at];
+        let right_node = FlatNode {
+            depth: depth + 1,
+            value: (value + 1) / 2,
+            prev: at,
+            next,
+        };
+        let right = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = right_node;
+                slot
+            }
+            None => {
+                self.nodes.push(right_node);
+                self.nodes.len() - 1
+            }
+        };
+        if next != NIL {
+            self.nodes[next].prev = right;
+        }
+        let node = &mut self.nodes[at];
+        node.depth += 1;
+        node.value = value / 2;
+        node.next = right;
+        at
+    }
+
+    /// Expects both halves of a sum to be reduced, so nothing is nested
+    /// deeper than 5 levels and every explode leaves a 0 at depth 4.
+    fn reduce(&mut self) {
+        let mut current = self.head;
+        while current != NIL {
+            if self.nodes[current].depth > 4 {
+                current = self.explode(current);
+            }
+            current = self.nodes[current].next;
+        }
+        // Everything left of `current` stays below 10, so the leftmost split
+        // is always the next one found.
+        current = self.head;
+        while current != NIL {
+            let node = self.nodes[current];
+            if node.value < 10 {
+                current = node.next;
+                continue;
+            }
+            current = self.split(current);
+            if node.depth + 1 > 4 {
+                current = self.explode(current);
+                let prev = self.nodes[current].prev;
+                if prev != NIL {
+                    current = prev;
+                }
+            }
+        }
+    }
+
+    fn add(&self, rh: &FlatSnailFish) -> FlatSnailFish {
+        let mut sum = FlatSnailFish::from_leaves(
+            self.leaves()
+                .chain(rh.leaves())
+                .map(|(depth, value)| (depth + 1, value)),
+        );
+        sum.reduce();
+        sum
+    }
+}
+
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let numbers = reader
         .lines()
         .map(|x| Ok(read_snail_fish(&x?)?))
         .collect::<MyResult<Vec<_>>>()?;
+    let flat_numbers = numbers
+        .iter()
+        .map(FlatSnailFish::from_tree)
+        .collect::<MyResult<Vec<_>>>()?;
+
+    let trace_format = args().nth(2);
+    if let Some(format @ ("--trace" | "--trace-json")) = trace_format.as_deref() {
+        let mut traces = Vec::new();
+        numbers.iter().cloned().reduce(|l, r| {
+            let (sum, trace) = l.add_traced(r);
+            traces.push(trace);
+            sum
+        });
+        if format == "--trace" {
+            for (addition_no, trace) in traces.iter().enumerate() {
+                println!("Addition {}:\n{}", addition_no + 1, trace);
+            }
+        } else {
+            println!("[{}]", traces.iter().map(ReductionTrace::to_json).join(","));
+        }
+    }
 
     println!(
         "Task 1: {}",
-        numbers
+        flat_numbers
             .iter()
             .cloned()
-            .reduce(|l, r| l.add(r))
-            .unwrap()
+            .reduce(|l, r| l.add(&r))
+            .ok_or("no numbers")?
             .compute_magnitude()
     );
     println!(
         "Task 2: {:?}",
-        numbers
+        flat_numbers
             .iter()
-            .combinations(2)
-            .flat_map(|v| [(v[0].clone(), v[1].clone()), (v[1].clone(), v[0].clone())])
-            .map(|(l, r)| l.add(r).compute_magnitude())
+            .permutations(2)
+            .map(|v| v[0].add(v[1]).compute_magnitude())
             .max()
     );
     Ok(())
@@ -223,6 +611,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_multi_digit_and_whitespace() -> MyResult<()> {
+        assert_eq!(
+            read_snail_fish(" [ 10 ,\t[3, 123] ] \n")?,
+            SnailFish::pair(Regular(10), SnailFish::pair(Regular(3), Regular(123)))
+        );
+        assert!(read_snail_fish("[1,2] 3").is_err());
+        assert!(read_snail_fish("[1 2]").is_err());
+        assert!(read_snail_fish("[99999999999,2]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_round_trip() -> MyResult<()> {
+        let mut ex = "[[[[4,3],4],4],[7,[[8,4],9]]]".parse::<SnailFish>()?;
+        ex = SnailFish::pair(ex, Regular(1));
+        // Only explode, so the unreduced [15,...] split candidates remain.
+        while ex.explode() {}
+        assert_eq!(ex.to_string(), "[[[[0,7],4],[15,[0,13]]],1]");
+        assert_eq!(ex.to_string().parse::<SnailFish>()?, ex);
+        Ok(())
+    }
+
     #[test]
     fn test_explode_left() -> MyResult<()> {
         let mut ex = read_snail_fish("[[[[[9,8],1],2],3],4]")?;
@@ -254,4 +665,103 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_trace() -> MyResult<()> {
+        let (sum, trace) =
+            read_snail_fish("[[[[4,3],4],4],[7,[[8,4],9]]]")?.add_traced(read_snail_fish("[1,1]")?);
+        assert_eq!(
+            trace.start.to_string(),
+            "[[[[[4,3],4],4],[7,[[8,4],9]]],[1,1]]"
+        );
+        let actions = trace
+            .steps
+            .iter()
+            .map(|x| x.0.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                "explode [4,3] at LLLL: 4 dropped, 3 pushed right",
+                "explode [8,4] at LRRL: 8 pushed left, 4 pushed right",
+                "split 15 at LRL",
+                "split 13 at LRRR",
+                "explode [6,7] at LRRR: 6 pushed left, 7 pushed right",
+            ]
+        );
+        let intermediate = trace
+            .steps
+            .iter()
+            .map(|x| x.1.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            intermediate,
+            [
+                "[[[[0,7],4],[7,[[8,4],9]]],[1,1]]",
+                "[[[[0,7],4],[15,[0,13]]],[1,1]]",
+                "[[[[0,7],4],[[7,8],[0,13]]],[1,1]]",
+                "[[[[0,7],4],[[7,8],[0,[6,7]]]],[1,1]]",
+                "[[[[0,7],4],[[7,8],[6,0]]],[8,1]]",
+            ]
+        );
+        assert_eq!(trace.steps.last().unwrap().1, sum);
+        assert!(trace.to_string().contains("  3. split 15 at LRL\n"));
+
+        let json = trace.to_json();
+        assert!(json.starts_with("{\"start\":[[[[[4,3],4],4],[7,[[8,4],9]]],[1,1]],\"steps\":[{\"action\":\"explode\",\"position\":\"LLLL\",\"pair\":[4,3],\"pushed_left\":null,\"pushed_right\":3,\"result\":[[[[0,7],4],[7,[[8,4],9]]],[1,1]]},"));
+        assert!(
+            json.contains("{\"action\":\"split\",\"position\":\"LRL\",\"value\":15,\"result\":")
+        );
+        assert!(json.ends_with("[[[[0,7],4],[[7,8],[6,0]]],[8,1]]}]}"));
+        Ok(())
+    }
+
+    const HOMEWORK: [&str; 10] = [
+        "[[[0,[5,8]],[[1,7],[9,6]]],[[4,[1,2]],[[1,4],2]]]",
+        "[[[5,[2,8]],4],[5,[[9,9],0]]]",
+        "[6,[[[6,2],[5,6]],[[7,6],[4,7]]]]",
+        "[[[6,[0,7]],[0,9]],[4,[9,[9,0]]]]",
+        "[[[7,[6,4]],[3,[1,3]]],[[[5,5],1],9]]",
+        "[[6,[[7,3],[3,2]]],[[[3,8],[5,7]],4]]",
+        "[[[[5,4],[7,7]],8],[[8,3],8]]",
+        "[[9,3],[[9,9],[6,[4,9]]]]",
+        "[[2,[[7,7],7]],[[5,8],[[9,3],[0,2]]]]",
+        "[[[[5,2],5],[8,[3,7]]],[[5,[7,5]],[4,4]]]",
+    ];
+
+    #[test]
+    fn test_flat_round_trip() -> MyResult<()> {
+        for line in HOMEWORK {
+            let tree = read_snail_fish(line)?;
+            let flat = FlatSnailFish::from_tree(&tree)?;
+            assert_eq!(flat.to_tree(), tree);
+            assert_eq!(flat.compute_magnitude(), tree.compute_magnitude());
+        }
+        assert!(FlatSnailFish::from_tree(&read_snail_fish("[[[[[1,2],3],4],5],6]")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flat_add_agrees_with_tree() -> MyResult<()> {
+        let trees = HOMEWORK
+            .iter()
+            .map(|line| read_snail_fish(line))
+            .collect::<MyResult<Vec<_>>>()?;
+        for pair in trees.iter().permutations(2) {
+            let expected = pair[0].clone().add(pair[1].clone());
+            let flat = FlatSnailFish::from_tree(pair[0])?.add(&FlatSnailFish::from_tree(pair[1])?);
+            assert_eq!(flat.to_tree(), expected);
+        }
+        let flats = trees
+            .iter()
+            .map(FlatSnailFish::from_tree)
+            .collect::<MyResult<Vec<_>>>()?;
+        let sum = flats.into_iter().reduce(|l, r| l.add(&r)).unwrap();
+        assert_eq!(
+            sum.to_tree(),
+            read_snail_fish("[[[[6,6],[7,6]],[[7,7],[7,0]]],[[[7,7],[7,7]],[[7,8],[9,9]]]]")?
+        );
+        assert_eq!(sum.compute_magnitude(), 4140);
+        Ok(())
+    }
 }
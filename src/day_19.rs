@@ -1,14 +1,21 @@
-use std::{collections::HashSet, io::BufRead};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    io::BufRead,
+};
 
 use crate::common::MyResult;
 use itertools::Itertools;
-use ndarray::{array, stack, Array1, Array2, Axis, Zip};
+use ndarray::{array, stack, Array1, Array2, ArrayView1, Axis, Zip};
+use std::env::args;
+use std::error::Error;
 use std::str::FromStr;
 
 #[derive(Debug)]
 struct Alignment {
     transformed: Array2<i32>,
     delta: Array1<i32>,
+    /// Maps column vectors in the scanner's frame to the cluster's frame.
+    rotation: Array2<i32>,
 }
 
 fn compute_orientations() -> Vec<Array2<i32>> {
@@ -36,46 +43,303 @@ fn compute_orientations() -> Vec<Array2<i32>> {
         .collect_vec()
 }
 
+#[derive(Clone, Debug)]
+pub struct AlignmentConfig {
+    /// Beacons two scanners must both detect to be linked.
+    pub min_overlap: usize,
+    /// Largest coordinate difference at which a scanner detects a beacon.
+    pub detection_range: i32,
+}
+
+impl Default for AlignmentConfig {
+    fn default() -> AlignmentConfig {
+        AlignmentConfig {
+            min_overlap: 12,
+            detection_range: 1000,
+        }
+    }
+}
+
+/// For every beacon, the sorted squared distances to the other beacons of the
+/// same scanner. Rotating or moving the scanner leaves them unchanged, so
+/// they can be compared before any orientation is known.
+struct Fingerprint {
+    per_beacon: Vec<Vec<i64>>,
+    all: Vec<i64>,
+}
+
+impl Fingerprint {
+    fn new(beacons: &Array2<i32>) -> Fingerprint {
+        let rows = beacons.axis_iter(Axis(0)).collect_vec();
+        let per_beacon = (0..rows.len())
+            .map(|i| {
+                let a = &rows[i];
+                let mut distances = (0..rows.len())
+                    .filter(|&j| j != i)
+                    .map(|j| {
+                        let b = &rows[j];
+                        Zip::from(a)
+                            .and(b)
+                            .fold(0, |acc, &x, &y| acc + (x - y) as i64 * (x - y) as i64)
+                    })
+                    .collect_vec();
+                distances.sort_unstable();
+                distances
+            })
+            .collect_vec();
+        // Each distance appears once for either end, keep one of them.
+        let all = per_beacon
+            .iter()
+            .flatten()
+            .copied()
+            .sorted_unstable()
+            .step_by(2)
+            .collect_vec();
+        Fingerprint { per_beacon, all }
+    }
+
+    /// Whether the two scanners have enough distances in common to possibly
+    /// share `min_overlap` beacons.
+    fn may_overlap(&self, other: &Fingerprint, min_overlap: usize) -> bool {
+        common_count(&self.all, &other.all) >= min_overlap * min_overlap.saturating_sub(1) / 2
+    }
+
+    /// Pairs `(reference beacon, other beacon)` that could be the same
+    /// beacon, most likely first.
+    fn correspondences(&self, other: &Fingerprint, min_overlap: usize) -> Vec<(usize, usize)> {
+        let mut candidates = Vec::new();
+        for (i, a) in self.per_beacon.iter().enumerate() {
+            for (j, b) in other.per_beacon.iter().enumerate() {
+                let common = common_count(a, b);
+                if common + 1 >= min_overlap {
+                    candidates.push((common, i, j));
+                }
+            }
+        }
+        candidates.sort_unstable_by_key(|c| std::cmp::Reverse(c.0));
+        candidates.into_iter().map(|(_, i, j)| (i, j)).collect()
+    }
+}
+
+/// Size of the multiset intersection of two sorted slices.
+fn common_count(a: &[i64], b: &[i64]) -> usize {
+    let (mut i, mut j, mut count) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                count += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Tries each orientation only for deltas implied by the candidate
+/// `correspondences` rather than for every pair of beacons.
 fn find_alignment(
     reference: &Array2<i32>,
     reference_delta: &Array1<i32>,
     to_transform: &Array2<i32>,
     orientations: &[Array2<i32>],
+    correspondences: &[(usize, usize)],
+    config: &AlignmentConfig,
 ) -> Option<Alignment> {
     let reference_set = reference
         .axis_iter(Axis(0))
         .map(|a| a.into_owned())
         .collect::<HashSet<_>>();
-    for orientation in orientations {
-        let oriented = to_transform.dot(orientation);
-        for r in reference.axis_iter(Axis(0)) {
-            'o_loop: for o in oriented.axis_iter(Axis(0)) {
-                let delta = &r - &o;
-
-                let mut overlap_size = 0;
-                for t in oriented.axis_iter(Axis(0)) {
-                    let t = &t + &delta;
-                    if (&t - reference_delta).mapv(i32::abs).iter().max().unwrap() >= &1_000 {
-                        continue;
-                    }
-                    if !reference_set.contains(&t) {
-                        continue 'o_loop;
-                    }
-                    overlap_size += 1;
-                }
+    let oriented_all = orientations
+        .iter()
+        .map(|orientation| (orientation, to_transform.dot(orientation)))
+        .collect_vec();
+    for &(r, o) in correspondences {
+        'o_loop: for (orientation, oriented) in &oriented_all {
+            let delta = &reference.row(r) - &oriented.row(o);
 
-                if overlap_size >= 12 {
-                    return Some(Alignment {
-                        transformed: &oriented + &delta,
-                        delta,
-                    });
+            let mut overlap_size = 0;
+            for t in oriented.axis_iter(Axis(0)) {
+                let t = &t + &delta;
+                if (&t - reference_delta).mapv(i32::abs).iter().max().unwrap()
+                    >= &config.detection_range
+                {
+                    continue;
+                }
+                if !reference_set.contains(&t) {
+                    continue 'o_loop;
                 }
+                overlap_size += 1;
+            }
+
+            if overlap_size >= config.min_overlap {
+                return Some(Alignment {
+                    transformed: oriented + &delta,
+                    delta,
+                    rotation: orientation.t().to_owned(),
+                });
             }
         }
     }
     None
 }
 
+/// Splits the scanners into clusters that can be linked to each other, each
+/// aligned breadth-first in the frame of its lowest-numbered scanner. Only
+/// pairs whose fingerprints allow an overlap are tried.
+fn assemble(scanners: &[Array2<i32>], config: &AlignmentConfig) -> Vec<Vec<(usize, Alignment)>> {
+    let orientations = compute_orientations();
+    let fingerprints = scanners.iter().map(Fingerprint::new).collect_vec();
+    let mut aligned: Vec<Option<Alignment>> = scanners.iter().map(|_| None).collect_vec();
+    let mut clusters = Vec::new();
+    while let Some(root) = aligned.iter().position(Option::is_none) {
+        aligned[root] = Some(Alignment {
+            transformed: scanners[root].clone(),
+            delta: array![0, 0, 0],
+            rotation: Array2::eye(3),
+        });
+        let mut members = vec![root];
+        let mut queue = VecDeque::from([root]);
+        while let Some(i) = queue.pop_front() {
+            for j in 0..scanners.len() {
+                if aligned[j].is_some()
+                    || !fingerprints[i].may_overlap(&fingerprints[j], config.min_overlap)
+                {
+                    continue;
+                }
+                let Alignment {
+                    transformed, delta, ..
+                } = aligned[i].as_ref().unwrap();
+                let correspondences =
+                    fingerprints[i].correspondences(&fingerprints[j], config.min_overlap);
+                if let Some(a) = find_alignment(
+                    transformed,
+                    delta,
+                    &scanners[j],
+                    &orientations,
+                    &correspondences,
+                    config,
+                ) {
+                    aligned[j] = Some(a);
+                    members.push(j);
+                    queue.push_back(j);
+                }
+            }
+        }
+        clusters.push(members);
+    }
+    clusters
+        .into_iter()
+        .map(|members| {
+            members
+                .into_iter()
+                .sorted()
+                .map(|i| (i, aligned[i].take().unwrap()))
+                .collect()
+        })
+        .collect()
+}
+
+fn cluster_beacons(cluster: &[(usize, Alignment)]) -> HashSet<Array1<i32>> {
+    cluster
+        .iter()
+        .flat_map(|(_, Alignment { transformed, .. })| {
+            transformed.axis_iter(Axis(0)).map(|a| a.into_owned())
+        })
+        .collect()
+}
+
+/// A beacon or scanner in the frame of its cluster. Beacons carry the
+/// lowest-numbered scanner that detected them.
+#[derive(Debug, PartialEq, Eq)]
+struct CloudPoint {
+    position: [i32; 3],
+    scanner: usize,
+    cluster: usize,
+    is_scanner: bool,
+}
+
+fn point_cloud(clusters: &[Vec<(usize, Alignment)>]) -> Vec<CloudPoint> {
+    let to_position = |a: ArrayView1<i32>| [a[0], a[1], a[2]];
+    let mut points = Vec::new();
+    for (cluster_no, cluster) in clusters.iter().enumerate() {
+        let mut first_seen_by = BTreeMap::new();
+        for (i, Alignment { transformed, .. }) in cluster {
+            for beacon in transformed.axis_iter(Axis(0)) {
+                first_seen_by.entry(to_position(beacon)).or_insert(*i);
+            }
+        }
+        points.extend(
+            first_seen_by
+                .into_iter()
+                .map(|(position, scanner)| CloudPoint {
+                    position,
+                    scanner,
+                    cluster: cluster_no,
+                    is_scanner: false,
+                }),
+        );
+        points.extend(
+            cluster
+                .iter()
+                .map(|(i, Alignment { delta, .. })| CloudPoint {
+                    position: to_position(delta.view()),
+                    scanner: *i,
+                    cluster: cluster_no,
+                    is_scanner: true,
+                }),
+        );
+    }
+    points
+}
+
+/// ASCII PLY with the same vertex lines as `to_xyz`.
+fn to_ply(points: &[CloudPoint]) -> String {
+    format!(
+        "ply\nformat ascii 1.0\nelement vertex {}\n\
+         property int x\nproperty int y\nproperty int z\n\
+         property int scanner\nproperty int cluster\nproperty uchar is_scanner\n\
+         end_header\n{}",
+        points.len(),
+        to_xyz(points)
+    )
+}
+
+/// One point per line as `x y z scanner cluster is_scanner`.
+fn to_xyz(points: &[CloudPoint]) -> String {
+    points
+        .iter()
+        .map(|point| {
+            format!(
+                "{} {} {} {} {} {}\n",
+                point.position[0],
+                point.position[1],
+                point.position[2],
+                point.scanner,
+                point.cluster,
+                point.is_scanner as u8
+            )
+        })
+        .collect()
+}
+
+fn flag_value<T: FromStr>(flags: &[String], name: &str) -> MyResult<Option<T>>
+where
+    T::Err: Error + 'static,
+{
+    match flags.iter().position(|x| x == name) {
+        Some(i) => Ok(Some(
+            flags
+                .get(i + 1)
+                .ok_or(format!("{} needs a value", name))?
+                .parse()?,
+        )),
+        None => Ok(None),
+    }
+}
+
 pub fn run_me(read: impl BufRead) -> MyResult<()> {
     let mut peekable_lines = read.lines().peekable();
     let mut scanners = Vec::new();
@@ -116,50 +380,224 @@ pub fn run_me(read: impl BufRead) -> MyResult<()> {
         }
     }
     // println!("{:?}", scanners);
-    let orientations = compute_orientations();
-    scanners.reverse();
-    let mut aligned = vec![Alignment {
-        transformed: scanners.pop().unwrap(),
-        delta: array![0, 0, 0],
-    }];
-    while scanners.len() > 0 {
-        let mut found = false;
-        for i in 0..scanners.len() {
-            let mut new_a = None;
-            for Alignment { transformed, delta } in &aligned {
-                if let Some(a) = find_alignment(transformed, delta, &scanners[i], &orientations) {
-                    new_a = Some(a);
-                    break;
-                }
-            }
-            if let Some(a) = new_a {
-                aligned.push(a);
-                scanners.remove(i);
-                found = true;
-                println!("found");
-                break;
-            }
+    let flags = args().skip(2).collect::<Vec<_>>();
+    let default_config = AlignmentConfig::default();
+    let config = AlignmentConfig {
+        min_overlap: flag_value(&flags, "--overlap")?.unwrap_or(default_config.min_overlap),
+        detection_range: flag_value(&flags, "--range")?.unwrap_or(default_config.detection_range),
+    };
+    let clusters = assemble(&scanners, &config);
+    for (cluster_no, cluster) in clusters.iter().enumerate() {
+        println!(
+            "Cluster {}: {} scanners, {} beacons",
+            cluster_no,
+            cluster.len(),
+            cluster_beacons(cluster).len()
+        );
+        for (
+            i,
+            Alignment {
+                delta, rotation, ..
+            },
+        ) in cluster
+        {
+            println!(
+                "  scanner {}: position {}, rotation {}",
+                i,
+                delta,
+                rotation.to_string().replace('\n', "")
+            );
         }
-        assert!(found);
     }
-    let beakon_set = aligned
-        .iter()
-        .flat_map(|Alignment { transformed, .. }| {
-            transformed
-                .axis_iter(Axis(0))
-                .map(|a| a.into_owned())
-                .collect_vec()
-        })
-        .collect::<HashSet<_>>();
-    println!("Task 1: {}", beakon_set.len());
+    if let [cluster] = &clusters[..] {
+        println!("Task 1: {}", cluster_beacons(cluster).len());
 
-    let max_distance = aligned
-        .iter()
-        .combinations(2)
-        .map(|v| (&v[0].delta - &v[1].delta).mapv(i32::abs).sum())
-        .max();
+        let max_distance = cluster
+            .iter()
+            .combinations(2)
+            .map(|v| (&v[0].1.delta - &v[1].1.delta).mapv(i32::abs).sum())
+            .max();
 
-    println!("Task 2: {:?}", max_distance);
+        println!("Task 2: {:?}", max_distance);
+    } else {
+        println!("Task 1: scanners form {} separate clusters", clusters.len());
+    }
+    if let Some(path) = flag_value::<String>(&flags, "--ply")? {
+        std::fs::write(&path, to_ply(&point_cloud(&clusters)))?;
+        println!("Wrote {}", path);
+    }
+    if let Some(path) = flag_value::<String>(&flags, "--xyz")? {
+        std::fs::write(&path, to_xyz(&point_cloud(&clusters)))?;
+        println!("Wrote {}", path);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+
+    /// Deterministic pseudo-random points in the cube `[-extent, extent)^3`.
+    fn random_points(count: usize, extent: i32, seed: u64) -> Vec<Array1<i32>> {
+        let mut rng = TestRng(seed);
+        let mut next = || rng.below(2 * extent as u64) as i32 - extent;
+        (0..count).map(|_| array![next(), next(), next()]).collect()
+    }
+
+    /// The beacons a scanner at `position` detects, in its own frame, such
+    /// that `orientation` turns them back into the global one.
+    fn scan(
+        beacons: &[Array1<i32>],
+        position: &Array1<i32>,
+        orientation: &Array2<i32>,
+    ) -> Array2<i32> {
+        let visible = beacons
+            .iter()
+            .map(|b| b - position)
+            .filter(|b| b.iter().all(|x| x.abs() < 1_000))
+            .map(|b| b.dot(&orientation.t()))
+            .collect_vec();
+        stack(Axis(0), &visible.iter().map(Array1::view).collect_vec()).unwrap()
+    }
+
+    fn rows(a: &Array2<i32>) -> HashSet<Array1<i32>> {
+        a.axis_iter(Axis(0)).map(|x| x.into_owned()).collect()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let beacons = random_points(1500, 2150, 19);
+        let orientations = compute_orientations();
+        let positions = [
+            array![0, 0, 0],
+            array![1100, 0, 0],
+            array![1100, 1000, 100],
+            array![-900, 50, -1000],
+        ];
+        let rotations = [0, 5, 13, 22];
+        let scanners = positions
+            .iter()
+            .zip(rotations)
+            .map(|(position, o)| scan(&beacons, position, &orientations[o]))
+            .collect_vec();
+
+        let clusters = assemble(&scanners, &AlignmentConfig::default());
+        assert_eq!(clusters.len(), 1);
+        for ((i, alignment), (position, o)) in
+            clusters[0].iter().zip(positions.iter().zip(rotations))
+        {
+            assert_eq!(&alignment.delta, position);
+            assert_eq!(alignment.rotation, orientations[o].t());
+            let expected = scan(&beacons, position, &orientations[0]) + position;
+            assert_eq!(
+                rows(&alignment.transformed),
+                rows(&expected),
+                "scanner {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let beacons = random_points(1500, 2150, 19);
+        let orientations = compute_orientations();
+        let near = scan(&beacons, &array![0, 0, 0], &orientations[0]);
+        let turned = scan(&beacons, &array![200, -300, 0], &orientations[7]);
+        let far = scan(&beacons, &array![2000, 2000, 2000], &orientations[3]);
+        let (near, turned, far) = (
+            Fingerprint::new(&near),
+            Fingerprint::new(&turned),
+            Fingerprint::new(&far),
+        );
+        assert!(near.may_overlap(&turned, 12));
+        assert!(!near.may_overlap(&far, 12));
+        assert_eq!(common_count(&[1, 2, 2, 2, 5], &[2, 2, 3, 5, 5]), 3);
+    }
+
+    #[test]
+    fn test_clusters_and_config() {
+        let beacons = random_points(1500, 2150, 19);
+        let orientations = compute_orientations();
+        let positions = [
+            array![0, 0, 0],
+            array![-1100, 200, 0],
+            array![1100, 0, 0],
+            array![2200, 0, 0],
+        ];
+        let scanners = positions
+            .iter()
+            .zip([0, 3, 9, 17])
+            .map(|(position, o)| scan(&beacons, position, &orientations[o]))
+            .collect_vec();
+
+        // Scanner 3 is linked to scanner 0 only through scanner 2.
+        // Demanding more overlap than any pair has splits everything.
+        let clusters = assemble(&scanners, &AlignmentConfig::default());
+        assert_eq!(clusters.len(), 1);
+        let strict = AlignmentConfig {
+            min_overlap: 1000,
+            ..AlignmentConfig::default()
+        };
+        let clusters = assemble(&scanners, &strict);
+        assert_eq!(clusters.iter().map(|c| c[0].0).collect_vec(), [0, 1, 2, 3]);
+
+        let separate = [
+            scanners[1].clone(),
+            scanners[3].clone(),
+            scanners[2].clone(),
+        ];
+        let clusters = assemble(&separate, &AlignmentConfig::default());
+        let members = clusters
+            .iter()
+            .map(|c| c.iter().map(|x| x.0).collect_vec())
+            .collect_vec();
+        assert_eq!(members, [vec![0], vec![1, 2]]);
+        // The second cluster lives in the frame of scanner 3.
+        let (_, second) = &clusters[1][1];
+        assert_eq!(
+            second.delta,
+            orientations[17].dot(&(&positions[2] - &positions[3]))
+        );
+        assert_eq!(second.rotation, orientations[17].dot(&orientations[9].t()));
+
+        let short_range = AlignmentConfig {
+            detection_range: 500,
+            ..AlignmentConfig::default()
+        };
+        assert_eq!(assemble(&scanners, &short_range).len(), 4);
+    }
+
+    #[test]
+    fn test_point_cloud() {
+        let scanners = [
+            array![[1, 2, 3], [4, 5, 6]],
+            array![[4, 5, 6], [7, 8, 9]],
+            array![[0, 0, 0]],
+        ];
+        let alignment = |i: usize, delta| Alignment {
+            transformed: scanners[i].clone(),
+            delta,
+            rotation: Array2::eye(3),
+        };
+        let clusters = vec![
+            vec![
+                (0, alignment(0, array![0, 0, 0])),
+                (1, alignment(1, array![10, 0, 0])),
+            ],
+            vec![(2, alignment(2, array![0, 0, 0]))],
+        ];
+        let points = point_cloud(&clusters);
+        assert_eq!(
+            to_xyz(&points),
+            "1 2 3 0 0 0\n4 5 6 0 0 0\n7 8 9 1 0 0\n0 0 0 0 0 1\n10 0 0 1 0 1\n\
+             0 0 0 2 1 0\n0 0 0 2 1 1\n"
+        );
+        let ply = to_ply(&points);
+        assert!(ply.starts_with("ply\nformat ascii 1.0\nelement vertex 7\n"));
+        assert!(ply.contains("\nend_header\n1 2 3 0 0 0\n"));
+        assert_eq!(ply.lines().count(), 10 + 7);
+    }
+}
@@ -1,94 +1,330 @@
-use itertools::Itertools;
-use ndarray::{s, stack, Array1, Array2, Axis, Dimension, Zip};
-
 use crate::MyResult;
 
-use std::io::{BufRead, Result as IOResult};
-
-fn enchance(image: Array2<u8>, lookup: &[u8], padding: u8) -> (Array2<u8>, u8) {
-    let (height, width) = image.raw_dim().into_pattern();
-    let mut padded_image = Array2::from_elem([height + 4, width + 4], padding);
-    padded_image
-        .slice_mut(s![2..(height + 2), 2..(width + 2)])
-        .assign(&image);
-    assert!(padded_image.is_standard_layout());
-    (
-        Zip::from(padded_image.windows([3, 3])).map_collect(|x| {
-            let num = usize::from_str_radix(
-                &x.axis_iter(Axis(0))
-                    .flat_map(|x| x)
-                    .map(|&c| match c {
-                        b'#' => '1',
-                        b'.' => '0',
-                        _ => panic!("unexpected byte {}", c),
-                    })
-                    .collect::<String>(),
-                2,
-            )
-            .unwrap();
-            lookup[num]
-        }),
-        lookup[if padding == b'.' { 0 } else { 511 }],
-    )
+use std::env::args;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::BufRead;
+
+#[derive(Debug)]
+enum ImageError {
+    LookupLength(usize),
+    UnexpectedByte {
+        line: usize,
+        column: usize,
+        byte: u8,
+    },
+    Ragged {
+        line: usize,
+        length: usize,
+        expected: usize,
+    },
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ImageError {}
+
+fn parse_pixel(byte: u8, line: usize, column: usize) -> Result<bool, ImageError> {
+    match byte {
+        b'#' => Ok(true),
+        b'.' => Ok(false),
+        _ => Err(ImageError::UnexpectedByte { line, column, byte }),
+    }
+}
+
+struct Lookup([bool; 512]);
+
+impl Lookup {
+    fn parse(line: &str) -> Result<Lookup, ImageError> {
+        if line.len() != 512 {
+            return Err(ImageError::LookupLength(line.len()));
+        }
+        let mut table = [false; 512];
+        for (column, byte) in line.bytes().enumerate() {
+            table[column] = parse_pixel(byte, 1, column)?;
+        }
+        Ok(Lookup(table))
+    }
+}
+
+/// A finite window of lit pixels packed 64 to a word, row by row, surrounded
+/// by an infinite background that is uniformly lit or dark.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Image {
+    height: usize,
+    width: usize,
+    words_per_row: usize,
+    bits: Vec<u64>,
+    background: bool,
+}
+
+impl Image {
+    fn new(height: usize, width: usize, background: bool) -> Image {
+        let words_per_row = width.div_ceil(64);
+        Image {
+            height,
+            width,
+            words_per_row,
+            bits: vec![0; height * words_per_row],
+            background,
+        }
+    }
+
+    /// Errors count lines of the whole input from 1, so the image starts
+    /// on line 3.
+    fn parse(lines: &[String]) -> Result<Image, ImageError> {
+        let width = lines.first().map_or(0, String::len);
+        let mut image = Image::new(lines.len(), width, false);
+        for (y, line) in lines.iter().enumerate() {
+            if line.len() != width {
+                return Err(ImageError::Ragged {
+                    line: y + 3,
+                    length: line.len(),
+                    expected: width,
+                });
+            }
+            for (x, byte) in line.bytes().enumerate() {
+                if parse_pixel(byte, y + 3, x)? {
+                    image.set(y, x);
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    fn set(&mut self, y: usize, x: usize) {
+        self.bits[y * self.words_per_row + x / 64] |= 1 << (x % 64);
+    }
+
+    /// Any coordinate is valid; those outside the window are background.
+    fn get(&self, y: i64, x: i64) -> bool {
+        if y < 0 || x < 0 || y >= self.height as i64 || x >= self.width as i64 {
+            return self.background;
+        }
+        let (y, x) = (y as usize, x as usize);
+        self.bits[y * self.words_per_row + x / 64] & (1 << (x % 64)) != 0
+    }
+
+    /// Grows the window by one pixel on each side, which is as far as the
+    /// finite part can spread; the rest follows the background.
+    fn enhance(&self, lookup: &Lookup) -> Image {
+        let background = lookup.0[if self.background { 511 } else { 0 }];
+        let mut enhanced = Image::new(self.height + 2, self.width + 2, background);
+        for y in 0..enhanced.height {
+            let source_y = y as i64 - 1;
+            let column = |x: i64| {
+                (self.get(source_y - 1, x) as usize) << 6
+                    | (self.get(source_y, x) as usize) << 3
+                    | self.get(source_y + 1, x) as usize
+            };
+            // Bits of the 3x3 window, row-major with the top left pixel
+            // first; each step right shifts in the next column.
+            let mut index = (column(-2) << 1) | column(-1);
+            for x in 0..enhanced.width {
+                index = ((index << 1) & 0b110_110_110) | column(x as i64);
+                if lookup.0[index] {
+                    enhanced.set(y, x);
+                }
+            }
+        }
+        enhanced
+    }
+
+    /// `None` when the background, and so the image, is lit infinitely.
+    fn lit_count(&self) -> Option<usize> {
+        if self.background {
+            return None;
+        }
+        Some(self.bits.iter().map(|x| x.count_ones() as usize).sum())
+    }
+}
+
+impl Display for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for y in 0..self.height {
+            let row = (0..self.width)
+                .map(|x| {
+                    if self.get(y as i64, x as i64) {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let mut lines = reader.lines();
 
-    let enchancement_lookup = lines.next().ok_or("no first line")??.into_bytes();
+    let lookup = Lookup::parse(&lines.next().ok_or("no first line")??)?;
 
     let empty_line = lines.next().ok_or("no second line")??;
+    if !empty_line.is_empty() {
+        Err(format!("unexpected second line {}", empty_line))?
+    }
+
+    let mut image = Image::parse(&lines.collect::<Result<Vec<_>, _>>()?)?;
+    let requested = match args().nth(2) {
+        Some(x) => Some(x.parse::<usize>()?),
+        None => None,
+    };
+    let describe = |image: &Image| match image.lit_count() {
+        Some(count) => count.to_string(),
+        None => "infinitely many, the background is lit".to_string(),
+    };
+    for step in 1..=requested.unwrap_or(50) {
+        image = image.enhance(&lookup);
+        match step {
+            2 => println!("Task 1: {}", describe(&image)),
+            50 => println!("Task 2: {}", describe(&image)),
+            _ => {}
+        }
+    }
+    if let Some(steps) = requested {
+        println!("After {} steps: {}", steps, describe(&image));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+    use std::collections::HashSet;
+
+    const EXAMPLE_LOOKUP: &str = "..#.#..#####.#.#.#.###.##.....###.##.#..###.####..#####..#....#..#..##..###..######.###...####..#..#####..##..#.#####...##.#.#..#.##..#.#......#.###.######.###.####...#.##.##..#..#..#####.....#.#....###..#.##......#.....#..#..#..##..#...##.######.####.####.#.#...#.......#..#.#.#...####.##.#......#..#...##.#.##..#...##.#.##..###.#......#.#.......#.#.#.####.###.##...#.....####.#..#..#.##.#....##..#.####....##...##..#...#......#.#.......#.......##..####..#...#.#.#...##..#.#..###..#####........#..####......#..#";
 
-    let input_image = stack(
-        Axis(0),
-        &lines
-            .map_ok(|x| Array1::from_vec(x.into_bytes()))
-            .collect::<IOResult<Vec<_>>>()?
-            .iter()
-            .map(Array1::view)
-            .collect::<Vec<_>>(),
-    )?;
-    let enchanced_2 = {
-        let mut x = input_image.clone();
-        let mut padding = b'.';
+    fn example_image() -> Image {
+        let lines = ["#..#.", "#....", "##..#", "..#..", "..###"];
+        Image::parse(&lines.map(String::from)).unwrap()
+    }
+
+    #[test]
+    fn test_example() -> MyResult<()> {
+        let lookup = Lookup::parse(EXAMPLE_LOOKUP)?;
+        let mut image = example_image();
         for _ in 0..2 {
-            let (new_x, new_padding) = enchance(x, &enchancement_lookup, padding);
-            x = new_x;
-            padding = new_padding;
+            image = image.enhance(&lookup);
         }
-        x
-    };
-    println!(
-        "Task 1: {}",
-        enchanced_2
-            .iter()
-            .map(|&x| match x {
-                b'#' => 1i32,
-                b'.' => 0i32,
-                _ => panic!("unexpected byte {}", x),
-            })
-            .sum::<i32>()
-    );
-    let enchanced_50 = {
-        let mut x = input_image.clone();
-        let mut padding = b'.';
-        for _ in 0..50 {
-            let (new_x, new_padding) = enchance(x, &enchancement_lookup, padding);
-            x = new_x;
-            padding = new_padding;
+        assert_eq!(image.lit_count(), Some(35));
+        for _ in 2..50 {
+            image = image.enhance(&lookup);
         }
-        x
-    };
-    println!(
-        "Task 2: {}",
-        enchanced_50
-            .iter()
-            .map(|&x| match x {
-                b'#' => 1i32,
-                b'.' => 0i32,
-                _ => panic!("unexpected byte {}", x),
+        assert_eq!(image.lit_count(), Some(3351));
+        Ok(())
+    }
+
+    /// Lit pixels as a set, with the background handled separately.
+    fn reference_enhance(
+        lit: &HashSet<(i64, i64)>,
+        background: bool,
+        bounds: (i64, i64, i64, i64),
+        lookup: &Lookup,
+    ) -> (HashSet<(i64, i64)>, bool) {
+        let (y0, y1, x0, x1) = bounds;
+        let pixel = |y: i64, x: i64| {
+            if y < y0 || y >= y1 || x < x0 || x >= x1 {
+                background
+            } else {
+                lit.contains(&(y, x))
+            }
+        };
+        let mut enhanced = HashSet::new();
+        for y in y0 - 1..y1 + 1 {
+            for x in x0 - 1..x1 + 1 {
+                let mut index = 0;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        index = index << 1 | pixel(y + dy, x + dx) as usize;
+                    }
+                }
+                if lookup.0[index] {
+                    enhanced.insert((y, x));
+                }
+            }
+        }
+        (enhanced, lookup.0[if background { 511 } else { 0 }])
+    }
+
+    #[test]
+    fn test_against_reference() {
+        let mut rng = TestRng(20);
+        for round in 0..4 {
+            let mut table = [false; 512];
+            table.iter_mut().for_each(|x| *x = rng.next_bool());
+            // Cover all four background behaviours.
+            table[0] = round & 1 == 1;
+            table[511] = round & 2 == 2;
+            let lookup = Lookup(table);
+
+            // Wider than one word, to cross word boundaries.
+            let mut image = Image::new(7, 70, false);
+            let mut lit = HashSet::new();
+            for y in 0..7 {
+                for x in 0..70 {
+                    if rng.next_bool() {
+                        image.set(y, x);
+                        lit.insert((y as i64, x as i64));
+                    }
+                }
+            }
+            let mut background = false;
+            for step in 0..5i64 {
+                let bounds = (-step, 7 + step, -step, 70 + step);
+                (lit, background) = reference_enhance(&lit, background, bounds, &lookup);
+                image = image.enhance(&lookup);
+                assert_eq!(image.background, background);
+                let packed = (0..image.height as i64)
+                    .flat_map(|y| (0..image.width as i64).map(move |x| (y, x)))
+                    .filter(|&(y, x)| image.get(y, x))
+                    .map(|(y, x)| (y - step - 1, x - step - 1))
+                    .collect::<HashSet<_>>();
+                assert_eq!(packed, lit, "round {} step {}", round, step);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flashing_background() -> MyResult<()> {
+        let mut table = ['.'; 512];
+        table[0] = '#';
+        let lookup = Lookup::parse(&table.iter().collect::<String>())?;
+        let image = Image::new(3, 3, false).enhance(&lookup);
+        assert!(image.background);
+        assert_eq!(image.lit_count(), None);
+        assert_eq!(image.to_string(), "#####\n".repeat(5));
+        let image = image.enhance(&lookup);
+        assert!(!image.background);
+        assert_eq!(image.lit_count(), Some(0));
+        assert_eq!(image.to_string(), ".......\n".repeat(7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            Lookup::parse("#."),
+            Err(ImageError::LookupLength(2))
+        ));
+        assert!(matches!(
+            Image::parse(&["#.".to_string(), "#".to_string()]),
+            Err(ImageError::Ragged { line: 4, .. })
+        ));
+        assert!(matches!(
+            Image::parse(&["#x".to_string()]),
+            Err(ImageError::UnexpectedByte {
+                line: 3,
+                column: 1,
+                ..
             })
-            .sum::<i32>()
-    );
-    Ok(())
+        ));
+    }
 }
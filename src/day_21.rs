@@ -1,39 +1,122 @@
 use std::io::BufRead;
 
-use ndarray::{s, ArrayD, Axis, IxDyn, Slice};
+use itertools::Itertools;
+use ndarray::{s, Array3, ArrayD, Axis, Dimension, IxDyn, Slice};
 
 use crate::common::MyResult;
+use num_bigint::BigUint;
+use std::env::args;
+use std::iter::repeat_n;
+use std::ops::{AddAssign, Mul};
 use std::str::FromStr;
 
-const LINE_PREFIX_1: &str = "Player 1 starting position: ";
-const LINE_PREFIX_2: &str = "Player 2 starting position: ";
+#[derive(Clone, Debug)]
+pub struct Rules {
+    pub board_size: usize,
+    pub die_faces: usize,
+    pub rolls_per_turn: usize,
+    pub target_score: usize,
+    pub player_count: usize,
+}
 
-const ROLLS_AND_FREQS: &[(usize, i128)] = &[
-    (3, 1), // 1,1,1
-    (4, 3), // 1,1,2 1,2,1 2,1,1
-    (5, 6), // 1,1,3 1,2,2
-    (6, 7), // 1,2,3 2,2,2
-    (7, 6),
-    (8, 3),
-    (9, 1),
-];
+impl Rules {
+    pub fn deterministic_game() -> Rules {
+        Rules {
+            board_size: 10,
+            die_faces: 100,
+            rolls_per_turn: 3,
+            target_score: 1000,
+            player_count: 2,
+        }
+    }
 
-pub fn run_me(reader: impl BufRead) -> MyResult<()> {
-    let mut lines = reader.lines();
-    let line_1 = lines.next().ok_or("line 1 is missing")??;
-    let line_2 = lines.next().ok_or("line 2 is missing")??;
-    for (line, prefix) in [(&line_1, LINE_PREFIX_1), (&line_2, LINE_PREFIX_2)] {
-        if !line.starts_with(prefix) {
-            Err(format!("{} doesn't start with {}", line, prefix))?;
+    pub fn dirac_game() -> Rules {
+        Rules {
+            board_size: 10,
+            die_faces: 3,
+            rolls_per_turn: 3,
+            target_score: 21,
+            player_count: 2,
+        }
+    }
+
+    /// Positions are numbered from 1.
+    fn advance(&self, position: usize, moves: usize) -> usize {
+        (position + moves - 1) % self.board_size + 1
+    }
+
+    /// Every total one turn can move, weighted by the roll sequences giving
+    /// it with each roll counting `face_weight`: 1 counts sequences, and
+    /// `1 / die_faces` gives probabilities.
+    fn roll_distribution<T>(&self, face_weight: T) -> Vec<(usize, T)>
+    where
+        T: Clone + Default + PartialEq + From<u8> + AddAssign + Mul<Output = T>,
+    {
+        let mut ways = vec![T::from(1)];
+        for _ in 0..self.rolls_per_turn {
+            let mut next = vec![T::default(); ways.len() + self.die_faces];
+            for (total, w) in ways.iter().enumerate() {
+                for face in 1..=self.die_faces {
+                    next[total + face] += w.clone() * face_weight.clone();
+                }
+            }
+            ways = next;
         }
+        ways.into_iter()
+            .enumerate()
+            .filter(|(_, w)| *w != T::default())
+            .collect()
     }
-    let player_1_pos = usize::from_str(&line_1[LINE_PREFIX_1.len()..])?;
-    let player_2_pos = usize::from_str(&line_2[LINE_PREFIX_2.len()..])?;
 
-    println!("{} {}", player_1_pos, player_2_pos);
+    /// Player to move, then each player's position, then each player's
+    /// score. Scores stop growing on the turn they reach the target, so they
+    /// stay below `target_score + board_size`.
+    fn memo_shape(&self) -> Vec<usize> {
+        let mut shape = vec![self.player_count];
+        shape.extend(repeat_n(self.board_size + 1, self.player_count));
+        shape.extend(repeat_n(
+            self.target_score + self.board_size,
+            self.player_count,
+        ));
+        shape
+    }
 
-    let mut positions = [player_1_pos, player_2_pos];
-    let mut points = [0, 0];
+    /// Calls `f` with the memo index of every state in which nobody has won
+    /// yet, each after all states that lead to it, or before them when going
+    /// `backwards`: a turn always raises the sum of the scores.
+    fn for_each_open_state(&self, backwards: bool, mut f: impl FnMut(&[usize])) {
+        let n = self.player_count;
+        let mut score_tuples = (0..n)
+            .map(|_| 0..self.target_score)
+            .multi_cartesian_product()
+            .sorted_by_key(|scores| scores.iter().sum::<usize>())
+            .collect_vec();
+        if backwards {
+            score_tuples.reverse();
+        }
+        let position_tuples = (0..n)
+            .map(|_| 1..=self.board_size)
+            .multi_cartesian_product()
+            .collect_vec();
+        let mut state = vec![0; 1 + 2 * n];
+        for scores in score_tuples {
+            state[1 + n..].copy_from_slice(&scores);
+            for player in 0..n {
+                state[0] = player;
+                for positions in &position_tuples {
+                    state[1..=n].copy_from_slice(positions);
+                    f(&state);
+                }
+            }
+        }
+    }
+}
+
+/// Plays with a die that rolls 1, 2, ... and wraps around after its last
+/// face. Returns the final scores and the number of rolls.
+fn play_deterministic(rules: &Rules, starts: &[usize]) -> (Vec<usize>, usize) {
+    let mut positions = starts.to_vec();
+    let mut points = vec![0; starts.len()];
     let mut n_rolls = 0;
     let mut current_player = 0;
     let mut current_dice_value = 1;
@@ -41,63 +124,391 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
     let mut roll = || {
         n_rolls += 1;
         let r = current_dice_value;
-        current_dice_value = 1 + current_dice_value % 1_000;
+        current_dice_value = 1 + current_dice_value % rules.die_faces;
         r
     };
 
-    while points.iter().max().unwrap() < &1_000 {
-        let moves = roll() + roll() + roll();
+    while points.iter().max().unwrap() < &rules.target_score {
+        let moves = (0..rules.rolls_per_turn).map(|_| roll()).sum();
         let p = &mut positions[current_player];
         // println!("{} {}", *p, moves);
-        *p = (*p + moves - 1) % 10 + 1;
+        *p = rules.advance(*p, moves);
         points[current_player] += *p;
         // println!("score {} {} {}", moves, *p, points[current_player]);
-        current_player = 1 - current_player;
+        current_player = (current_player + 1) % rules.player_count;
     }
+    (points, n_rolls)
+}
 
-    println!("Task 1: {}", points.iter().min().unwrap() * n_rolls);
+/// Spreads a weight of 1 from the starting state over every reachable
+/// state, each roll total passing on its weight in `rolls` times as much.
+fn spread_over_states<T>(rules: &Rules, starts: &[usize], rolls: &[(usize, T)]) -> ArrayD<T>
+where
+    T: Clone + Default + From<u8> + AddAssign + Mul<Output = T>,
+{
+    let n = rules.player_count;
+    let mut memo = ArrayD::from_elem(IxDyn(&rules.memo_shape()), T::default());
+    let mut index = vec![0; 1 + 2 * n];
+    index[1..=n].copy_from_slice(starts);
+    memo[IxDyn(&index)] = T::from(1);
+    let mut next = index;
+    rules.for_each_open_state(false, |state| {
+        let current = memo[IxDyn(state)].clone();
+        let player = state[0];
+        next.copy_from_slice(state);
+        next[0] = (player + 1) % n;
+        for (total, weight) in rolls {
+            let position = rules.advance(state[1 + player], *total);
+            next[1 + player] = position;
+            next[1 + n + player] = state[1 + n + player] + position;
+            memo[IxDyn(&next)] += weight.clone() * current.clone();
+        }
+    });
+    memo
+}
 
-    let mut memo = ArrayD::from_elem(IxDyn(&[2, 11, 11, 31, 31]), 0i128);
-    memo[[0, player_1_pos, player_2_pos, 0, 0]] = 1;
-    for score_sum in 0..=40 {
-        for player_1_score in 0..=score_sum {
-            let player_2_score = score_sum - player_1_score;
-            if player_1_score >= 21 || player_2_score >= 21 {
-                continue;
+/// The number of universes in which each player wins, which outgrows any
+/// fixed-width integer for long games.
+fn count_wins(rules: &Rules, starts: &[usize]) -> Vec<BigUint> {
+    let n = rules.player_count;
+    let rolls = rules.roll_distribution(BigUint::from(1u32));
+    let memo = spread_over_states(rules, starts, &rolls);
+    (0..n)
+        .map(|player| {
+            memo.slice_axis(Axis(1 + n + player), Slice::from(rules.target_score..))
+                .iter()
+                .sum()
+        })
+        .collect()
+}
+
+/// How a Dirac game is expected to play out, with every roll total as
+/// likely as the number of ways to roll it. Players are numbered from 0.
+pub struct DiracAnalysis {
+    rules: Rules,
+    /// Chance that a player starts a turn at `[player, position, score]`.
+    turn_reach: Array3<f64>,
+    /// The same, restricted to games the player goes on to win.
+    turn_wins: Array3<f64>,
+    win_chances: Vec<f64>,
+    expected_turns: f64,
+    losing_scores: Vec<f64>,
+}
+
+impl DiracAnalysis {
+    pub fn new(rules: Rules, starts: &[usize]) -> DiracAnalysis {
+        let n = rules.player_count;
+        // Weighting each face by its chance gives each total's chance
+        // directly, without counting the outcomes of a whole turn.
+        let rolls = rules.roll_distribution(1.0 / rules.die_faces as f64);
+        let reach = spread_over_states(&rules, starts, &rolls);
+
+        // Chance of each player winning from each open state, by the same
+        // recursion run backwards; the last axis picks the player.
+        let mut shape = rules.memo_shape();
+        shape.push(n);
+        let mut win_chance = ArrayD::zeros(IxDyn(&shape));
+        let mut next = vec![0; 2 + 2 * n];
+        let mut chances = vec![0.0; n];
+        rules.for_each_open_state(true, |state| {
+            let mover = state[0];
+            next[..state.len()].copy_from_slice(state);
+            next[0] = (mover + 1) % n;
+            chances.fill(0.0);
+            for &(total, p) in &rolls {
+                let position = rules.advance(state[1 + mover], total);
+                let score = state[1 + n + mover] + position;
+                if score >= rules.target_score {
+                    chances[mover] += p;
+                    continue;
+                }
+                next[1 + mover] = position;
+                next[1 + n + mover] = score;
+                for (player, chance) in chances.iter_mut().enumerate() {
+                    next[1 + 2 * n] = player;
+                    *chance += p * win_chance[IxDyn(&next)];
+                }
+            }
+            let mut index = state.to_vec();
+            index.push(0);
+            for (player, &chance) in chances.iter().enumerate() {
+                index[1 + 2 * n] = player;
+                win_chance[IxDyn(&index)] = chance;
             }
-            for current_player in [0, 1] {
-                for player_1_pos in 1..=10 {
-                    for player_2_pos in 1..=10 {
-                        for (roll, freq) in ROLLS_AND_FREQS {
-                            let mut positions = [player_1_pos, player_2_pos];
-                            let mut scores = [player_1_score, player_2_score];
-                            let p = &mut positions[current_player];
-                            // println!("{} {}", *p, moves);
-                            *p = (*p + roll - 1) % 10 + 1;
-                            scores[current_player] += *p;
-                            memo[[
-                                1 - current_player,
-                                positions[0],
-                                positions[1],
-                                scores[0],
-                                scores[1],
-                            ]] += freq
-                                * memo[[
-                                    current_player,
-                                    player_1_pos,
-                                    player_2_pos,
-                                    player_1_score,
-                                    player_2_score,
-                                ]];
-                        }
-                    }
+        });
+
+        let turn_shape = (n, rules.board_size + 1, rules.target_score);
+        let mut turn_reach = Array3::zeros(turn_shape);
+        let mut turn_wins = Array3::zeros(turn_shape);
+        let mut expected_turns = 0.0;
+        rules.for_each_open_state(false, |state| {
+            let mover = state[0];
+            let p = reach[IxDyn(state)];
+            let cell = [mover, state[1 + mover], state[1 + n + mover]];
+            let mut index = state.to_vec();
+            index.push(mover);
+            turn_reach[cell] += p;
+            turn_wins[cell] += p * win_chance[IxDyn(&index)];
+            expected_turns += p;
+        });
+
+        let mut win_chances = vec![0.0; n];
+        let mut losing_scores = vec![0.0; rules.target_score];
+        for (index, &p) in reach.indexed_iter() {
+            let scores = &index.slice()[1 + n..];
+            if let Some(winner) = scores.iter().position(|&x| x >= rules.target_score) {
+                win_chances[winner] += p;
+                let lowest = scores.iter().filter(|&&x| x < rules.target_score).min();
+                if let Some(&lowest) = lowest {
+                    losing_scores[lowest] += p;
                 }
             }
         }
+
+        DiracAnalysis {
+            rules,
+            turn_reach,
+            turn_wins,
+            win_chances,
+            expected_turns,
+            losing_scores,
+        }
+    }
+
+    pub fn win_chances(&self) -> &[f64] {
+        &self.win_chances
+    }
+
+    /// Chance that `player` starts a turn at `position` with `score` points.
+    pub fn turn_probability(&self, player: usize, position: usize, score: usize) -> f64 {
+        self.turn_reach[[player, position, score]]
+    }
+
+    /// Chance that `player` wins once they start a turn at `position` with
+    /// `score` points, or `None` if that cannot happen.
+    pub fn win_probability(&self, player: usize, position: usize, score: usize) -> Option<f64> {
+        let reach = self.turn_probability(player, position, score);
+        if reach > 0.0 {
+            Some(self.turn_wins[[player, position, score]] / reach)
+        } else {
+            None
+        }
+    }
+
+    /// Turns taken by all players together.
+    pub fn expected_turns(&self) -> f64 {
+        self.expected_turns
+    }
+
+    /// Chance of each final score of the lowest-scoring player, by score.
+    pub fn losing_scores(&self) -> &[f64] {
+        &self.losing_scores
+    }
+
+    /// One row per reachable turn start, players numbered from 1.
+    pub fn win_table_csv(&self) -> String {
+        let mut csv = String::from("player,position,score,turn_probability,win_probability\n");
+        for ((player, position, score), &reach) in self.turn_reach.indexed_iter() {
+            if let Some(win) = self.win_probability(player, position, score) {
+                csv += &format!("{},{},{},{},{}\n", player + 1, position, score, reach, win);
+            }
+        }
+        csv
+    }
+
+    pub fn losing_scores_csv(&self) -> String {
+        let mut csv = String::from("losing_score,probability\n");
+        for (score, p) in self.losing_scores.iter().enumerate() {
+            csv += &format!("{},{}\n", score, p);
+        }
+        csv
+    }
+}
+
+fn read_starts(reader: impl BufRead, board_size: usize) -> MyResult<Vec<usize>> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line?;
+            let prefix = format!("Player {} starting position: ", i + 1);
+            if !line.starts_with(&prefix) {
+                Err(format!("{} doesn't start with {}", line, prefix))?;
+            }
+            let position = usize::from_str(&line[prefix.len()..])?;
+            if !(1..=board_size).contains(&position) {
+                Err(format!("position {} is not on the board", position))?;
+            }
+            Ok(position)
+        })
+        .collect()
+}
+
+pub fn run_me(reader: impl BufRead) -> MyResult<()> {
+    let starts = read_starts(reader, Rules::deterministic_game().board_size)?;
+    if starts.is_empty() {
+        Err("no players")?;
+    }
+
+    println!("{:?}", starts);
+
+    let rules = Rules {
+        player_count: starts.len(),
+        ..Rules::deterministic_game()
+    };
+    let (points, n_rolls) = play_deterministic(&rules, &starts);
+    println!("Task 1: {}", points.iter().min().unwrap() * n_rolls);
+
+    let rules = Rules {
+        player_count: starts.len(),
+        ..Rules::dirac_game()
+    };
+    let wins = count_wins(&rules, &starts);
+    println!("{:?}", wins);
+    println!("Task 2: {}", wins.iter().max().unwrap());
+
+    let analysis = DiracAnalysis::new(rules, &starts);
+    println!("Win chances: {:?}", analysis.win_chances());
+    println!("Expected turns: {}", analysis.expected_turns());
+    let table = match args().nth(2).as_deref() {
+        Some("--win-table") => Some(analysis.win_table_csv()),
+        Some("--losing-scores") => Some(analysis.losing_scores_csv()),
+        _ => None,
+    };
+    if let Some(table) = table {
+        let path = args().nth(3).ok_or("table export needs an output file")?;
+        std::fs::write(&path, table)?;
+        println!("Wrote {}", path);
     }
-    let player_1_wins = memo.slice_axis(Axis(3), Slice::from(21..)).sum();
-    let player_2_wins = memo.slice_axis(Axis(4), Slice::from(21..)).sum();
-    println!("{} {}", player_1_wins, player_2_wins);
-    println!("Task 2: {}", player_1_wins.max(player_2_wins));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derived_tables() {
+        let rules = Rules::dirac_game();
+        assert_eq!(
+            rules.roll_distribution(1u128),
+            [(3, 1), (4, 3), (5, 6), (6, 7), (7, 6), (8, 3), (9, 1)]
+        );
+        assert_eq!(rules.memo_shape(), [2, 11, 11, 31, 31]);
+    }
+
+    #[test]
+    fn test_example() {
+        let (points, n_rolls) = play_deterministic(&Rules::deterministic_game(), &[4, 8]);
+        assert_eq!(points.iter().min().unwrap() * n_rolls, 739785);
+        assert_eq!(
+            count_wins(&Rules::dirac_game(), &[4, 8]),
+            [444356092776315u64, 341960390180808].map(BigUint::from)
+        );
+    }
+
+    #[test]
+    fn test_long_game() {
+        // A small board keeps scores low, so the game lasts long enough for
+        // the universe counts to outgrow u128.
+        let rules = Rules {
+            board_size: 3,
+            target_score: 40,
+            ..Rules::dirac_game()
+        };
+        let wins = count_wins(&rules, &[1, 2]);
+        assert!(wins.iter().all(|x| *x > BigUint::from(u128::MAX)));
+
+        // The same spread in floating point agrees to its precision.
+        let rolls = rules
+            .roll_distribution(1.0)
+            .into_iter()
+            .collect::<Vec<(usize, f64)>>();
+        let memo = spread_over_states(&rules, &[1, 2], &rolls);
+        for (player, exact) in wins.iter().enumerate() {
+            let approximate = memo
+                .slice_axis(Axis(3 + player), Slice::from(rules.target_score..))
+                .sum();
+            let exact = exact.to_string().parse::<f64>().unwrap();
+            assert!((approximate / exact - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_single_outcome_dice() {
+        // A one-faced die leaves a single universe, which must play out like
+        // the deterministic game with the same die.
+        let rules = Rules {
+            board_size: 5,
+            die_faces: 1,
+            rolls_per_turn: 2,
+            target_score: 12,
+            player_count: 3,
+        };
+        let (points, _) = play_deterministic(&rules, &[1, 4, 3]);
+        let winner = points.iter().position(|&x| x >= 12).unwrap();
+        let mut expected = vec![BigUint::default(); 3];
+        expected[winner] = BigUint::from(1u32);
+        assert_eq!(count_wins(&rules, &[1, 4, 3]), expected);
+    }
+
+    #[test]
+    fn test_analysis() {
+        let analysis = DiracAnalysis::new(Rules::dirac_game(), &[4, 8]);
+        let total = analysis.win_chances().iter().sum::<f64>();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!((analysis.losing_scores().iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        // Forward and backward passes agree on the opening position.
+        assert_eq!(analysis.turn_probability(0, 4, 0), 1.0);
+        for player in 0..2 {
+            let start = [4, 8][player];
+            let from_start = analysis.win_probability(player, start, 0).unwrap();
+            assert!((from_start - analysis.win_chances()[player]).abs() < 1e-9);
+        }
+        // Scores include the square just landed on.
+        assert_eq!(analysis.win_probability(0, 10, 1), None);
+        // With 20 points any roll wins.
+        assert!(analysis
+            .win_probability(0, 1, 20)
+            .is_some_and(|x| (x - 1.0).abs() < 1e-9));
+        // At least 3 turns each are needed to reach 21 points.
+        assert!(analysis.expected_turns() > 5.0);
+
+        let csv = analysis.win_table_csv();
+        assert!(csv.starts_with("player,position,score,turn_probability,win_probability\n"));
+        assert!(csv.contains("\n1,4,0,1,"));
+        assert_eq!(analysis.losing_scores_csv().lines().count(), 1 + 21);
+    }
+
+    #[test]
+    fn test_many_rolls_analysis() {
+        // 10^20 outcomes per turn, more than fit in a usize.
+        let rules = Rules {
+            die_faces: 10,
+            rolls_per_turn: 20,
+            ..Rules::dirac_game()
+        };
+        let analysis = DiracAnalysis::new(rules, &[4, 8]);
+        let total = analysis.win_chances().iter().sum::<f64>();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(analysis.win_chances().iter().all(|&x| x > 0.0));
+    }
+
+    #[test]
+    fn test_single_outcome_analysis() {
+        let rules = Rules {
+            board_size: 4,
+            die_faces: 1,
+            rolls_per_turn: 2,
+            target_score: 8,
+            player_count: 3,
+        };
+        let (points, n_rolls) = play_deterministic(&rules, &[1, 4, 3]);
+        let analysis = DiracAnalysis::new(rules, &[1, 4, 3]);
+        assert_eq!(analysis.expected_turns(), (n_rolls / 2) as f64);
+        let lowest = *points.iter().min().unwrap();
+        assert_eq!(analysis.losing_scores()[lowest], 1.0);
+        let winner = points.iter().position(|&x| x >= 8).unwrap();
+        assert_eq!(analysis.win_chances()[winner], 1.0);
+    }
+}
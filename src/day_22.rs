@@ -19,43 +19,85 @@ struct Instruction<R: Clone> {
     zs: R,
 }
 
-struct Remapper {
-    m: HashMap<i32, usize>,
-    numbers: Vec<i32>,
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Cuboid {
+    xs: RangeInclusive<i32>,
+    ys: RangeInclusive<i32>,
+    zs: RangeInclusive<i32>,
 }
 
-impl Remapper {
-    fn new(ranges: impl Iterator<Item = RangeInclusive<i32>>) -> Self {
-        let mut numbers = ranges
-            .flat_map(|a| [*a.start(), *a.end() + 1])
-            .collect_vec();
-        numbers.sort();
-        numbers.dedup();
-        Remapper {
-            m: numbers
-                .iter()
-                .cloned()
-                .enumerate()
-                .map(|(i, v)| (v, i))
-                .collect(),
-            numbers,
-        }
+fn intersect_ranges(
+    a: &RangeInclusive<i32>,
+    b: &RangeInclusive<i32>,
+) -> Option<RangeInclusive<i32>> {
+    let r = *a.start().max(b.start())..=*a.end().min(b.end());
+    if r.is_empty() {
+        None
+    } else {
+        Some(r)
     }
+}
 
-    fn map(&self, r: RangeInclusive<i32>) -> Range<usize> {
-        self.m[&r.start()]..self.m[&(r.end() + 1)]
+impl Cuboid {
+    fn intersect(&self, other: &Cuboid) -> Option<Cuboid> {
+        Some(Cuboid {
+            xs: intersect_ranges(&self.xs, &other.xs)?,
+            ys: intersect_ranges(&self.ys, &other.ys)?,
+            zs: intersect_ranges(&self.zs, &other.zs)?,
+        })
     }
 
-    fn region_size(&self, r: usize) -> i32 {
-        self.numbers[r + 1] - self.numbers[r]
+    /// A cuboid spanning the whole `i32` range holds about 2^96 cubes, so
+    /// volumes take `i128`.
+    fn volume(&self) -> i128 {
+        [&self.xs, &self.ys, &self.zs]
+            .iter()
+            .map(|r| (*r.end() as i128) - (*r.start() as i128) + 1)
+            .product()
     }
 }
 
-pub fn run_me(reader: impl BufRead) -> MyResult<()> {
+/// Counts lit cubes by inclusion-exclusion: each step cancels its overlap
+/// with every cuboid counted so far, then adds itself if it switches cubes
+/// on. Equal cuboids share one entry, so the cost grows with the number of
+/// distinct overlaps rather than with the extent of the coordinates.
+fn lit_volume(instructions: &[Instruction<RangeInclusive<i32>>]) -> i128 {
+    let mut signed_cuboids = HashMap::<Cuboid, i64>::new();
+    for Instruction {
+        new_value,
+        xs,
+        ys,
+        zs,
+    } in instructions
+    {
+        let cuboid = Cuboid {
+            xs: xs.clone(),
+            ys: ys.clone(),
+            zs: zs.clone(),
+        };
+        let mut updates = signed_cuboids
+            .iter()
+            .filter_map(|(other, &sign)| Some((cuboid.intersect(other)?, -sign)))
+            .collect_vec();
+        if *new_value {
+            updates.push((cuboid, 1));
+        }
+        for (cuboid, sign) in updates {
+            *signed_cuboids.entry(cuboid).or_insert(0) += sign;
+        }
+        signed_cuboids.retain(|_, sign| *sign != 0);
+    }
+    signed_cuboids
+        .iter()
+        .map(|(cuboid, &sign)| sign as i128 * cuboid.volume())
+        .sum()
+}
+
+fn read_instructions(reader: impl BufRead) -> MyResult<Vec<Instruction<RangeInclusive<i32>>>> {
     let parse_regex =
         Regex::new("(on|off) x=(-?\\d+)..(-?\\d+),y=(-?\\d+)..(-?\\d+),z=(-?\\d+)..(-?\\d+)")?;
 
-    let instructions = reader
+    reader
         .lines()
         .map(|line| {
             let line = line?;
@@ -76,7 +118,11 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
                 zs: i32::from_str(zb)?..=i32::from_str(ze)?,
             })
         })
-        .collect::<MyResult<Vec<_>>>()?;
+        .collect()
+}
+
+pub fn run_me(reader: impl BufRead) -> MyResult<()> {
+    let instructions = read_instructions(reader)?;
     // println!("{:?}", instructions);
 
     let mut reactor = Array3::from_elem([101, 101, 101], false);
@@ -100,58 +146,149 @@ pub fn run_me(reader: impl BufRead) -> MyResult<()> {
 
     println!("Task 1: {}", reactor.mapv(|x| x as i32).sum());
 
-    let x_remapper = Remapper::new(instructions.iter().map(|i| i.xs.clone()));
-    let y_remapper = Remapper::new(instructions.iter().map(|i| i.ys.clone()));
-    let z_remapper = Remapper::new(instructions.iter().map(|i| i.zs.clone()));
+    println!("Task 2: {}", lit_volume(&instructions));
 
-    let remapped_instructions = instructions
-        .iter()
-        .cloned()
-        .map(
-            |Instruction {
-                 new_value,
-                 xs,
-                 ys,
-                 zs,
-             }| Instruction {
-                new_value,
-                xs: x_remapper.map(xs),
-                ys: y_remapper.map(ys),
-                zs: z_remapper.map(zs),
-            },
-        )
-        .collect_vec();
-
-    let mut remapped_reactor = Array3::from_elem(
-        [
-            x_remapper.numbers.len() - 1,
-            y_remapper.numbers.len() - 1,
-            z_remapper.numbers.len() - 1,
-        ],
-        false,
-    );
+    Ok(())
+}
 
-    for Instruction {
-        new_value,
-        xs,
-        ys,
-        zs,
-    } in &remapped_instructions
-    {
-        remapped_reactor
-            .slice_mut(s![xs.clone(), ys.clone(), zs.clone()])
-            .fill(*new_value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TestRng;
+
+    struct Remapper {
+        m: HashMap<i32, usize>,
+        numbers: Vec<i32>,
+    }
+
+    impl Remapper {
+        fn new(ranges: impl Iterator<Item = RangeInclusive<i32>>) -> Self {
+            let mut numbers = ranges
+                .flat_map(|a| [*a.start(), *a.end() + 1])
+                .collect_vec();
+            numbers.sort();
+            numbers.dedup();
+            Remapper {
+                m: numbers
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .map(|(i, v)| (v, i))
+                    .collect(),
+                numbers,
+            }
+        }
+
+        fn map(&self, r: RangeInclusive<i32>) -> Range<usize> {
+            self.m[r.start()]..self.m[&(r.end() + 1)]
+        }
+
+        fn region_size(&self, r: usize) -> i32 {
+            self.numbers[r + 1] - self.numbers[r]
+        }
     }
-    println!(
-        "Task 2: {}",
-        remapped_reactor
+
+    /// The reactor on a grid compressed to the distinct cuboid boundaries.
+    fn compressed_grid_volume(instructions: &[Instruction<RangeInclusive<i32>>]) -> i128 {
+        let x_remapper = Remapper::new(instructions.iter().map(|i| i.xs.clone()));
+        let y_remapper = Remapper::new(instructions.iter().map(|i| i.ys.clone()));
+        let z_remapper = Remapper::new(instructions.iter().map(|i| i.zs.clone()));
+        let mut reactor = Array3::from_elem(
+            [
+                x_remapper.numbers.len() - 1,
+                y_remapper.numbers.len() - 1,
+                z_remapper.numbers.len() - 1,
+            ],
+            false,
+        );
+        for i in instructions {
+            reactor
+                .slice_mut(s![
+                    x_remapper.map(i.xs.clone()),
+                    y_remapper.map(i.ys.clone()),
+                    z_remapper.map(i.zs.clone())
+                ])
+                .fill(i.new_value);
+        }
+        reactor
             .indexed_iter()
-            .map(|((i, j, k), v)| (*v as u128)
-                * (x_remapper.region_size(i) as u128)
-                * (y_remapper.region_size(j) as u128)
-                * (z_remapper.region_size(k) as u128))
-            .sum::<u128>()
-    );
+            .map(|((i, j, k), v)| {
+                (*v as i128)
+                    * (x_remapper.region_size(i) as i128)
+                    * (y_remapper.region_size(j) as i128)
+                    * (z_remapper.region_size(k) as i128)
+            })
+            .sum()
+    }
 
-    Ok(())
+    #[test]
+    fn test_small_example() -> MyResult<()> {
+        let instructions = read_instructions(
+            &b"on x=10..12,y=10..12,z=10..12\n\
+               on x=11..13,y=11..13,z=11..13\n\
+               off x=9..11,y=9..11,z=9..11\n\
+               on x=10..10,y=10..10,z=10..10"[..],
+        )?;
+        assert_eq!(lit_volume(&instructions), 39);
+        assert_eq!(compressed_grid_volume(&instructions), 39);
+        Ok(())
+    }
+
+    #[test]
+    fn test_against_compressed_grid() {
+        let mut rng = TestRng(22);
+        let mut next = |n: u64| rng.below(n) as i32;
+        for round in 0..20 {
+            let instructions = (0..30)
+                .map(|_| {
+                    let new_value = next(3) > 0;
+                    let mut range = || {
+                        let start = next(60) - 30;
+                        start..=start + next(25)
+                    };
+                    let (xs, ys, zs) = (range(), range(), range());
+                    Instruction {
+                        new_value,
+                        xs,
+                        ys,
+                        zs,
+                    }
+                })
+                .collect_vec();
+            assert_eq!(
+                lit_volume(&instructions),
+                compressed_grid_volume(&instructions),
+                "round {}",
+                round
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_coordinates() {
+        let big = -100_000..=99_999;
+        let instructions = [
+            Instruction {
+                new_value: true,
+                xs: big.clone(),
+                ys: big.clone(),
+                zs: big.clone(),
+            },
+            Instruction {
+                new_value: false,
+                xs: 0..=99_999,
+                ys: big.clone(),
+                zs: big,
+            },
+        ];
+        assert_eq!(lit_volume(&instructions), 4_000_000_000_000_000);
+
+        let full = Instruction {
+            new_value: true,
+            xs: i32::MIN..=i32::MAX,
+            ys: i32::MIN..=i32::MAX,
+            zs: i32::MIN..=i32::MAX,
+        };
+        assert_eq!(lit_volume(&[full]), 1 << 96);
+    }
 }